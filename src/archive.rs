@@ -0,0 +1,353 @@
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind, Result};
+use std::str;
+
+/// Name of the tar entry holding data of a znode.
+pub const DATA_FILE_NAME: &str = "____data";
+/// Name of the tar entry holding `ZnodeMeta` of a znode, it precedes the data entry.
+pub const META_FILE_NAME: &str = "____meta";
+/// Name of the tar entry describing the whole dump with `Manifest`. It is the last entry of the archive,
+/// as the zxid of the dump is only known at the end, archives of older versions have it first.
+pub const MANIFEST_FILE_NAME: &str = "____manifest";
+
+/// Length of the counter zookeeper appends to names of sequential znodes.
+pub const SEQUENCE_LENGTH: usize = 10;
+
+/// Ephemeral owner zookeeper 3.5+ sets for container znodes.
+const CONTAINER_EPHEMERAL_OWNER: i64 = i64::MIN;
+/// Top byte of the ephemeral owner zookeeper 3.5+ sets for extended znode types, the next two bytes are the type.
+const EXTENDED_EPHEMERAL_OWNER_MASK: u64 = 0xffff_ff00_0000_0000;
+const TTL_EPHEMERAL_OWNER: u64 = 0xff00_0000_0000_0000;
+const TTL_MASK: i64 = 0x0000_00ff_ffff_ffff;
+
+/// What the ephemeral owner field of a znode stat tells about the znode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Owner {
+    None,
+    Session(i64),
+    Container,
+    /// Time to live in milliseconds
+    Ttl(i64),
+}
+
+impl Owner {
+    pub fn from_ephemeral_owner(ephemeral_owner: i64) -> Owner {
+        if ephemeral_owner == 0 {
+            Owner::None
+        } else if ephemeral_owner == CONTAINER_EPHEMERAL_OWNER {
+            Owner::Container
+        } else if ephemeral_owner as u64 & EXTENDED_EPHEMERAL_OWNER_MASK == TTL_EPHEMERAL_OWNER {
+            Owner::Ttl(ephemeral_owner & TTL_MASK)
+        } else {
+            Owner::Session(ephemeral_owner)
+        }
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(*self, Owner::Session(_))
+    }
+}
+
+/// The way a znode was created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZnodeMode {
+    Persistent,
+    PersistentSequential,
+    Container,
+    /// Recorded for information only, such znodes are never restored
+    Ephemeral,
+    /// Tombstone of a znode which is gone since it was recorded, restore deletes it along with its subtree
+    Deleted,
+}
+
+impl ZnodeMode {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ZnodeMode::Persistent => "persistent",
+            ZnodeMode::PersistentSequential => "persistent_sequential",
+            ZnodeMode::Container => "container",
+            ZnodeMode::Ephemeral => "ephemeral",
+            ZnodeMode::Deleted => "deleted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ZnodeMode> {
+        [ZnodeMode::Persistent, ZnodeMode::PersistentSequential, ZnodeMode::Container, ZnodeMode::Ephemeral, ZnodeMode::Deleted].iter()
+            .cloned()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+/// Everything about a znode beyond its data needed to recreate it.
+/// Stored in the archive as `key=value` lines, unknown keys are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZnodeMeta {
+    pub mode: ZnodeMode,
+    /// Time to live in milliseconds of a persistent znode
+    pub ttl: Option<i64>,
+    /// Session owning an ephemeral znode
+    pub session: Option<i64>,
+    /// Zxid of the last change of the data when dumped
+    pub mzxid: Option<i64>,
+    /// Zxid of the last change of the children when dumped
+    pub pzxid: Option<i64>,
+}
+
+impl Default for ZnodeMeta {
+    fn default() -> ZnodeMeta {
+        ZnodeMeta { mode: ZnodeMode::Persistent, ttl: None, session: None, mzxid: None, pzxid: None }
+    }
+}
+
+impl ZnodeMeta {
+    pub fn for_znode(znode_path: &str, ephemeral_owner: i64) -> ZnodeMeta {
+        let znode_name = znode_path.rsplit('/').next().unwrap_or("");
+        let owner = Owner::from_ephemeral_owner(ephemeral_owner);
+        let mode = match (owner, sequence_number(znode_name)) {
+            (Owner::Session(_), _) => ZnodeMode::Ephemeral,
+            (Owner::Container, _) => ZnodeMode::Container,
+            // Only a guess by the name, zookeeper doesn't tell, so restore recreates them only where asked to
+            (_, Some(_)) => ZnodeMode::PersistentSequential,
+            (_, None) => ZnodeMode::Persistent,
+        };
+        let ttl = match owner {
+            Owner::Ttl(ttl) => Some(ttl),
+            _ => None,
+        };
+        let session = match owner {
+            Owner::Session(session) => Some(session),
+            _ => None,
+        };
+        ZnodeMeta { mode, ttl, session, mzxid: None, pzxid: None }
+    }
+
+    pub fn tombstone() -> ZnodeMeta {
+        ZnodeMeta { mode: ZnodeMode::Deleted, ..Default::default() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut content = format!("mode={}\n", self.mode.name());
+        if let Some(ttl) = self.ttl {
+            content += &format!("ttl={}\n", ttl);
+        }
+        if let Some(session) = self.session {
+            content += &format!("session={}\n", format_session(session));
+        }
+        if let Some(mzxid) = self.mzxid {
+            content += &format!("mzxid={}\n", format_zxid(mzxid));
+        }
+        if let Some(pzxid) = self.pzxid {
+            content += &format!("pzxid={}\n", format_zxid(pzxid));
+        }
+        content.into_bytes()
+    }
+
+    pub fn parse(content: &[u8]) -> Result<ZnodeMeta> {
+        let mut meta = ZnodeMeta::default();
+        for (line, key, value) in key_values(content)? {
+            match key {
+                "mode" => meta.mode = ZnodeMode::from_name(value).ok_or_else(|| invalid_data(line))?,
+                "ttl" => meta.ttl = Some(value.parse().map_err(|_| invalid_data(line))?),
+                "session" => meta.session = Some(parse_hex(line, value)?),
+                "mzxid" => meta.mzxid = Some(parse_hex(line, value)?),
+                "pzxid" => meta.pzxid = Some(parse_hex(line, value)?),
+                _ => {}
+            }
+        }
+        Ok(meta)
+    }
+}
+
+/// Facts about the whole dump, stored the same way as `ZnodeMeta`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// Chroot the znode paths in the archive are relative to
+    pub chroot: Option<String>,
+    /// Highest zxid of the dumped znodes, the archive holds them as they all were at one moment after it
+    pub zxid: Option<i64>,
+    /// Zxid of the archive an incremental dump holds the changes since
+    pub base_zxid: Option<i64>,
+    /// How many znodes the dumped trees had, unchanged ones of an incremental dump included,
+    /// so that the next dump of them can tell how long it takes
+    pub znodes: Option<usize>,
+}
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut content = String::new();
+        if let Some(ref chroot) = self.chroot {
+            content += &format!("chroot={}\n", chroot);
+        }
+        if let Some(zxid) = self.zxid {
+            content += &format!("zxid={}\n", format_zxid(zxid));
+        }
+        if let Some(base_zxid) = self.base_zxid {
+            content += &format!("base_zxid={}\n", format_zxid(base_zxid));
+        }
+        if let Some(znodes) = self.znodes {
+            content += &format!("znodes={}\n", znodes);
+        }
+        content.into_bytes()
+    }
+
+    pub fn parse(content: &[u8]) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for (line, key, value) in key_values(content)? {
+            match key {
+                "chroot" => manifest.chroot = Some(String::from(value)),
+                "zxid" => manifest.zxid = Some(parse_hex(line, value)?),
+                "base_zxid" => manifest.base_zxid = Some(parse_hex(line, value)?),
+                "znodes" => manifest.znodes = Some(value.parse().map_err(|_| invalid_data(line))?),
+                _ => {}
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// Splits `key=value` lines, returning every line together with its key and value.
+fn key_values(content: &[u8]) -> Result<Vec<(&str, &str, &str)>> {
+    let content = str::from_utf8(content).map_err(|_| invalid_data("archive metadata isn't UTF-8"))?;
+    content.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut key_value = line.splitn(2, '=');
+            let key = key_value.next().unwrap_or("");
+            let value = key_value.next().ok_or_else(|| invalid_data(line))?;
+            Ok((line, key, value))
+        })
+        .collect()
+}
+
+/// Formats a session id the way zookeeper logs it.
+pub fn format_session(session: i64) -> String {
+    format!("0x{:x}", session as u64)
+}
+
+/// Formats a zxid the way zookeeper logs it.
+pub fn format_zxid(zxid: i64) -> String {
+    format!("0x{:x}", zxid as u64)
+}
+
+/// Parses a session id or zxid formatted the way zookeeper logs it, failing with the line it comes from.
+pub fn parse_hex(line: &str, value: &str) -> Result<i64> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map(|number| number as i64)
+        .map_err(|_| invalid_data(line))
+}
+
+/// Counter of a znode name looking like the one of a sequential znode, e.g. `lock-0000000042`.
+pub fn sequence_number(znode_name: &str) -> Option<u64> {
+    znode_name.len()
+        .checked_sub(SEQUENCE_LENGTH)
+        .and_then(|start| znode_name.get(start..))
+        .filter(|counter| counter.bytes().all(|byte| byte.is_ascii_digit()))
+        .and_then(|counter| counter.parse().ok())
+}
+
+/// Order of children in the archive: ordinary znodes by name, then sequential ones
+/// by their counter, so that restore can recreate them in the original order.
+pub fn compare_children(left: &str, right: &str) -> Ordering {
+    (sequence_number(left), left).cmp(&(sequence_number(right), right))
+}
+
+fn invalid_data(content: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Malformed archive content: '{}'", content))
+}
+
+#[cfg(test)]
+mod tests {
+    use archive::{compare_children, sequence_number, Manifest, Owner, ZnodeMeta, ZnodeMode};
+
+    #[test]
+    pub fn sequence_number_test() {
+        assert_eq!(sequence_number("lock-0000000042"), Some(42));
+        assert_eq!(sequence_number("0000000007"), Some(7));
+        assert_eq!(sequence_number("lock-42"), None);
+        assert_eq!(sequence_number("banana"), None);
+        assert_eq!(sequence_number("ä-000000042"), None);
+    }
+
+    #[test]
+    pub fn children_order() {
+        let mut children = vec!["write-0000000002", "banana", "read-0000000003", "apple", "read-0000000001"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<String>>();
+        children.sort_by(|left, right| compare_children(left, right));
+        assert_eq!(children, ["apple", "banana", "read-0000000001", "write-0000000002", "read-0000000003"]);
+    }
+
+    #[test]
+    pub fn meta_round_trip() {
+        let meta = ZnodeMeta::for_znode("/locks/lock-0000000042", 0);
+        assert_eq!(meta.mode, ZnodeMode::PersistentSequential);
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+        let meta = ZnodeMeta::for_znode("/", 0);
+        assert_eq!(meta.mode, ZnodeMode::Persistent);
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+        let meta = ZnodeMeta::for_znode("/locks", i64::MIN);
+        assert_eq!(meta.mode, ZnodeMode::Container);
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+        let meta = ZnodeMeta::for_znode("/cache/item-0000000003", 0xff00_0000_0000_ea60_u64 as i64);
+        assert_eq!(meta, ZnodeMeta { mode: ZnodeMode::PersistentSequential, ttl: Some(60000), ..Default::default() });
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+        let meta = ZnodeMeta::for_znode("/brokers/ids/1", 0xfe00_0178_5cd4_0002_u64 as i64);
+        assert_eq!(meta, ZnodeMeta { mode: ZnodeMode::Ephemeral, session: Some(0xfe00_0178_5cd4_0002_u64 as i64), ..Default::default() });
+        assert_eq!(String::from_utf8(meta.to_bytes()).unwrap(), "mode=ephemeral\nsession=0xfe0001785cd40002\n");
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+        let meta = ZnodeMeta { mzxid: Some(0x1_0000_002a), pzxid: Some(0x1_0000_0030), ..ZnodeMeta::for_znode("/brokers", 0) };
+        assert_eq!(String::from_utf8(meta.to_bytes()).unwrap(), "mode=persistent\nmzxid=0x10000002a\npzxid=0x100000030\n");
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+        let meta = ZnodeMeta::tombstone();
+        assert_eq!(String::from_utf8(meta.to_bytes()).unwrap(), "mode=deleted\n");
+        assert_eq!(ZnodeMeta::parse(&meta.to_bytes()).unwrap(), meta);
+    }
+
+    #[test]
+    pub fn owner() {
+        assert_eq!(Owner::from_ephemeral_owner(0), Owner::None);
+        assert_eq!(Owner::from_ephemeral_owner(0x0100_0178_5cd4_0002), Owner::Session(0x0100_0178_5cd4_0002));
+        assert_eq!(Owner::from_ephemeral_owner(i64::MIN), Owner::Container);
+        assert_eq!(Owner::from_ephemeral_owner(0xff00_0000_0000_03e8_u64 as i64), Owner::Ttl(1000));
+        assert!(Owner::from_ephemeral_owner(0x0100_0178_5cd4_0002).is_ephemeral());
+        assert!(!Owner::from_ephemeral_owner(i64::MIN).is_ephemeral());
+        assert!(!Owner::from_ephemeral_owner(0xff00_0000_0000_03e8_u64 as i64).is_ephemeral());
+    }
+
+    #[test]
+    pub fn meta_parse() {
+        assert_eq!(ZnodeMeta::parse(b"").unwrap(), ZnodeMeta::default());
+        assert_eq!(ZnodeMeta::parse(b"unknown=1\nmode=persistent_sequential\n").unwrap().mode, ZnodeMode::PersistentSequential);
+        assert!(ZnodeMeta::parse(b"mode=unknown").is_err());
+        assert!(ZnodeMeta::parse(b"mode").is_err());
+        assert_eq!(ZnodeMeta::parse(b"mode=container").unwrap().mode, ZnodeMode::Container);
+        assert_eq!(ZnodeMeta::parse(b"ttl=1000").unwrap().ttl, Some(1000));
+        assert!(ZnodeMeta::parse(b"ttl=banana").is_err());
+        assert_eq!(ZnodeMeta::parse(b"session=0x1000178").unwrap().session, Some(0x1000178));
+        assert!(ZnodeMeta::parse(b"session=0xbanana").is_err());
+        assert_eq!(ZnodeMeta::parse(b"mzxid=0x2a").unwrap().mzxid, Some(0x2a));
+        assert!(ZnodeMeta::parse(b"pzxid=banana").is_err());
+    }
+
+    #[test]
+    pub fn manifest_round_trip() {
+        let manifest = Manifest { chroot: Some(String::from("/kafka-a")), zxid: Some(0x1_0000_002a), ..Default::default() };
+        assert_eq!(String::from_utf8(manifest.to_bytes()).unwrap(), "chroot=/kafka-a\nzxid=0x10000002a\n");
+        assert_eq!(Manifest::parse(&manifest.to_bytes()).unwrap(), manifest);
+
+        let manifest = Manifest { base_zxid: Some(0x1_0000_0010), znodes: Some(42), ..manifest };
+        assert_eq!(String::from_utf8(manifest.to_bytes()).unwrap(), "chroot=/kafka-a\nzxid=0x10000002a\nbase_zxid=0x100000010\nznodes=42\n");
+        assert_eq!(Manifest::parse(&manifest.to_bytes()).unwrap(), manifest);
+        assert_eq!(Manifest::parse(b"").unwrap(), Manifest::default());
+        assert!(Manifest::parse(b"chroot").is_err());
+        assert!(Manifest::parse(b"zxid=banana").is_err());
+        assert!(Manifest::parse(b"znodes=-1").is_err());
+    }
+}
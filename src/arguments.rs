@@ -1,15 +1,40 @@
 extern crate clap;
 
+use std::time::Duration;
+
 use clap::{App, Arg};
 
 const SERVERS_ENV: &'static str = "ZKLOADER_SERVERS";
 const ZNODES_ENV: &'static str = "ZKLOADER_ZNODES";
 const FILE_ENV: &'static str = "ZKLOADER_FILE";
 const EXCLUDED_ENV: &'static str = "ZKLOADER_EXCLUDED";
+const BACKUP_BEFORE_ENV: &'static str = "ZKLOADER_BACKUP_BEFORE";
+const JOURNAL_ENV: &'static str = "ZKLOADER_JOURNAL";
+const SEQUENTIAL_ENV: &'static str = "ZKLOADER_SEQUENTIAL";
+const SESSION_TIMEOUT_ENV: &'static str = "ZKLOADER_SESSION_TIMEOUT";
+const CONNECT_TIMEOUT_ENV: &'static str = "ZKLOADER_CONNECT_TIMEOUT";
+const RETRIES_ENV: &'static str = "ZKLOADER_RETRIES";
+const RETRY_BACKOFF_ENV: &'static str = "ZKLOADER_RETRY_BACKOFF";
+const CHROOT_ENV: &'static str = "ZKLOADER_CHROOT";
+const TLS_CA_ENV: &'static str = "ZKLOADER_TLS_CA";
+const TLS_CERT_ENV: &'static str = "ZKLOADER_TLS_CERT";
+const TLS_KEY_ENV: &'static str = "ZKLOADER_TLS_KEY";
+const PARALLELISM_ENV: &'static str = "ZKLOADER_PARALLELISM";
+const LOG_FORMAT_ENV: &'static str = "ZKLOADER_LOG_FORMAT";
+const REPORT_ENV: &'static str = "ZKLOADER_REPORT";
+const ON_EPHEMERAL_ENV: &'static str = "ZKLOADER_ON_EPHEMERAL";
+const PROTECTED_ENV: &'static str = "ZKLOADER_PROTECTED";
 
 const SERVERS_DEFAULT: &'static str = "127.0.0.1:2181";
 const ZNODES_DEFAULT: &'static str = "/";
 const FILE_DEFAULT: &'static str = "zk-dump.tar.gz";
+const SESSION_TIMEOUT_DEFAULT: &'static str = "15s";
+const CONNECT_TIMEOUT_DEFAULT: &'static str = "15s";
+const RETRIES_DEFAULT: &'static str = "3";
+const RETRY_BACKOFF_DEFAULT: &'static str = "100ms";
+const PARALLELISM_DEFAULT: &'static str = "8";
+const EPHEMERAL_WAIT_DEFAULT: &'static str = "1m";
+const COMPACT_EVERY_DEFAULT: &'static str = "1h";
 
 pub fn args_parser_config<'a, 'b>() -> App<'a, 'b> {
     App::new("zk-loader")
@@ -23,16 +48,17 @@ pub fn args_parser_config<'a, 'b>() -> App<'a, 'b> {
                 .help("Dump data from znode to file")
                 .takes_value(false)
                 .required(true)
-                .conflicts_with_all(&["restore", "delete", "help"]),
+                .conflicts_with_all(&["restore", "delete", "rollback", "help"]),
         )
         .arg(
             Arg::with_name("restore")
                 .short("r")
                 .long("restore")
-                .help("Restore data from file to znode")
+                .help("Restore data from file to znode, znodes with a TTL fail along with their subtrees, as the client can't \
+                       create them")
                 .takes_value(false)
                 .required(true)
-                .conflicts_with_all(&["dump", "delete", "help"]),
+                .conflicts_with_all(&["dump", "delete", "rollback", "help"]),
         )
         .arg(
             Arg::with_name("delete")
@@ -40,18 +66,35 @@ pub fn args_parser_config<'a, 'b>() -> App<'a, 'b> {
                 .help("Delete znodes recursively")
                 .takes_value(false)
                 .required(true)
-                .conflicts_with_all(&["restore", "dump", "file", "help"])
+                .conflicts_with_all(&["restore", "dump", "rollback", "file", "help"])
+        )
+        .arg(
+            Arg::with_name("rollback")
+                .long("rollback")
+                .value_name("JOURNAL")
+                .help("Revert changes recorded in a journal of restore or delete")
+                .required(true)
+                .conflicts_with_all(&["restore", "dump", "delete", "file", "help"])
         )
         .arg(
             Arg::with_name("servers")
                 .short("s")
                 .long("servers")
                 .value_name("SERVERS")
-                .help("Zookeeper hosts")
+                .help("Zookeeper hosts, optionally followed by a chroot, e.g. 'zk1:2181,zk2:2181/kafka'")
                 .env(SERVERS_ENV)
                 .required(true)
                 .default_value(SERVERS_DEFAULT),
         )
+        .arg(
+            Arg::with_name("chroot")
+                .long("chroot")
+                .value_name("PATH")
+                .help("Chroot znode paths are relative to, overrides the one in servers. \
+                       Restore creates it if it's absent, so the dump of one chroot can be loaded into another")
+                .env(CHROOT_ENV)
+                .validator(|value| if value.starts_with('/') { Ok(()) } else { Err(String::from("Chroot must start with '/'")) }),
+        )
         .arg(
             Arg::with_name("znodes")
                 .short("z")
@@ -82,15 +125,342 @@ pub fn args_parser_config<'a, 'b>() -> App<'a, 'b> {
                 .env(EXCLUDED_ENV)
                 .use_delimiter(true),
         )
+        .arg(
+            Arg::with_name("protected")
+                .long("protected")
+                .value_name("ZNODES")
+                .help("Znodes which restore and delete never change anything in")
+                .env(PROTECTED_ENV)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("allow-protected")
+                .long("allow-protected")
+                .help("Restore or delete in protected znodes all the same")
+                .takes_value(false)
+                .conflicts_with_all(&["dump", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("Overwrite or delete existing znodes without asking for confirmation, a run which isn't confirmed exits with code 3")
+                .takes_value(false)
+                .conflicts_with_all(&["dump", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("check-versions")
+                .long("check-versions")
+                .help("Leave znodes changed by someone else since planning untouched and exit with code 2 if there are any")
+                .takes_value(false)
+                .conflicts_with_all(&["dump", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("require-existing")
+                .long("require-existing")
+                .help("Delete nothing and exit with an error if any of the znodes to delete doesn't exist")
+                .takes_value(false)
+                .conflicts_with_all(&["dump", "restore", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("on-ephemeral")
+                .long("on-ephemeral")
+                .value_name("POLICY")
+                .help("What to do when znodes to delete include ephemeral ones: delete nothing ('abort', default), \
+                       delete the rest leaving them and their ancestors in place ('skip') \
+                       or wait for their sessions to expire ('wait')")
+                .env(ON_EPHEMERAL_ENV)
+                .possible_values(&["abort", "skip", "wait"])
+                .conflicts_with_all(&["dump", "restore", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("ephemeral-wait")
+                .long("ephemeral-wait")
+                .value_name("DURATION")
+                .help("How long '--on-ephemeral wait' waits before deleting nothing")
+                .validator(validate_duration)
+                .default_value(EPHEMERAL_WAIT_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("backup-before")
+                .long("backup-before")
+                .value_name("FILE")
+                .help("Dump affected znodes to this file before restoring or deleting, abort if it fails")
+                .env(BACKUP_BEFORE_ENV)
+                .conflicts_with_all(&["dump", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .value_name("FILE")
+                .help("Record every change of restore or delete to this file, so it can be rolled back")
+                .env(JOURNAL_ENV)
+                .conflicts_with_all(&["dump", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("sequential")
+                .long("sequential")
+                .value_name("POLICY")
+                .help("How to restore sequential znodes: keep their names creating persistent znodes ('preserve', default) \
+                       or create sequential znodes again in the original order ('recreate')")
+                .env(SEQUENTIAL_ENV)
+                .possible_values(&["preserve", "recreate"])
+                .conflicts_with_all(&["dump", "delete", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("sequential-parents")
+                .long("sequential-parents")
+                .value_name("ZNODES")
+                .help("Znodes whose children were created as sequential ones, which is all '--sequential=recreate' \
+                       recreates, as sequential znodes can't be told apart from others by their names")
+                .use_delimiter(true)
+                .requires("sequential"),
+        )
+        .arg(
+            Arg::with_name("include-ephemeral")
+                .long("include-ephemeral")
+                .help("Record ephemeral znodes with their owner sessions in the dump, they are never restored")
+                .takes_value(false)
+                .conflicts_with_all(&["restore", "delete", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("incremental-from")
+                .long("incremental-from")
+                .value_name("FILE")
+                .help("Dump only the znodes changed since this archive, along with the ones removed since, \
+                       restore the archive and then the incremental dump to get the tree back")
+                .conflicts_with_all(&["restore", "delete", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .help("Keep running after the dump, appending changes of the znodes to a change log \
+                       and compacting it into the dump file now and then")
+                .takes_value(false)
+                .requires("dump")
+                .conflicts_with("incremental-from"),
+        )
+        .arg(
+            Arg::with_name("change-log")
+                .long("change-log")
+                .value_name("FILE")
+                .help("Where to append the changes seen by --watch, the dump file with '.changes' appended by default")
+                .requires("watch"),
+        )
+        .arg(
+            Arg::with_name("compact-every")
+                .long("compact-every")
+                .value_name("DURATION")
+                .help("How often --watch compacts the change log into the dump file")
+                .validator(validate_interval)
+                .default_value(COMPACT_EVERY_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("every")
+                .long("every")
+                .value_name("DURATION")
+                .help("Keep running and dump this often, naming every dump file after the dump file template \
+                       with '%Y', '%m', '%d', '%H', '%M', '%S' in its file name replaced by the UTC start time, \
+                       a template without them gets the start time in front of its extension")
+                .validator(validate_interval)
+                .requires("dump")
+                .conflicts_with("watch"),
+        )
+        .arg(
+            Arg::with_name("keep-last")
+                .long("keep-last")
+                .value_name("COUNT")
+                .help("Prune dump files of --every in the directory of the template except this many newest ones \
+                       and the ones kept by --keep-daily")
+                .validator(validate_retention)
+                .requires("every"),
+        )
+        .arg(
+            Arg::with_name("keep-daily")
+                .long("keep-daily")
+                .value_name("DAYS")
+                .help("Prune dump files of --every in the directory of the template except the newest one of each \
+                       of this many last days and the ones kept by --keep-last")
+                .validator(validate_retention)
+                .requires("every"),
+        )
+        .arg(
+            Arg::with_name("list-ephemeral")
+                .long("list-ephemeral")
+                .help("List ephemeral znodes recorded in the dump, which are skipped by restore")
+                .takes_value(false)
+                .conflicts_with_all(&["dump", "delete", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("parallelism")
+                .long("parallelism")
+                .value_name("COUNT")
+                .help("How many znodes to read concurrently while dumping or write while restoring, \
+                       the archive content and the restored tree don't depend on it")
+                .env(PARALLELISM_ENV)
+                .validator(|value| match value.parse::<usize>() {
+                    Ok(parallelism) if parallelism > 0 => Ok(()),
+                    _ => Err(String::from("Parallelism must be a positive number")),
+                })
+                .default_value(PARALLELISM_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Don't report progress and summary on stderr")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .value_name("FILE")
+                .help("Write a JSON report of the run into this file")
+                .env(REPORT_ENV)
+                .conflicts_with("rollback"),
+        )
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .help("Abort the run on the first znode which can't be read or written, the default")
+                .takes_value(false)
+                .conflicts_with("keep-going"),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Carry on past znodes which can't be read or written, list them and exit with an error at the end")
+                .takes_value(false)
+                .conflicts_with_all(&["fail-fast", "rollback"]),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Log znodes written, skipped and excluded, repeat to log every znode visited")
+                .multiple(true)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Format of the log records on stderr")
+                .env(LOG_FORMAT_ENV)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("session-timeout")
+                .long("session-timeout")
+                .value_name("DURATION")
+                .help("Zookeeper session timeout, e.g. '30s' or '1m'")
+                .env(SESSION_TIMEOUT_ENV)
+                .validator(validate_duration)
+                .default_value(SESSION_TIMEOUT_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("DURATION")
+                .help("Give up if the connection isn't established within this time")
+                .env(CONNECT_TIMEOUT_ENV)
+                .validator(validate_duration)
+                .default_value(CONNECT_TIMEOUT_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .value_name("COUNT")
+                .help("How many times to retry an operation failed because of connection loss or timeout")
+                .env(RETRIES_ENV)
+                .validator(|value| value.parse::<u32>().map(|_| ()).map_err(|err| err.to_string()))
+                .default_value(RETRIES_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("retry-backoff")
+                .long("retry-backoff")
+                .value_name("DURATION")
+                .help("Pause before the first retry, it doubles with every next one")
+                .env(RETRY_BACKOFF_ENV)
+                .validator(validate_duration)
+                .default_value(RETRY_BACKOFF_DEFAULT),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Connect to the secure client port over TLS, implied by any of the TLS options")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .value_name("FILE")
+                .help("PEM bundle of CA certificates to trust in addition to the system ones")
+                .env(TLS_CA_ENV),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("FILE")
+                .help("PEM client certificate")
+                .env(TLS_CERT_ENV)
+                .requires("tls-key"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("FILE")
+                .help("PEM PKCS#8 private key of the client certificate")
+                .env(TLS_KEY_ENV)
+                .requires("tls-cert"),
+        )
+}
+
+/// Parses durations like `500ms`, `15s`, `2m` or `1h`, a bare number means seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| format!("Invalid duration: '{}'", value))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => amount.checked_mul(60).map(Duration::from_secs).ok_or_else(|| format!("Duration too long: '{}'", value)),
+        "h" => amount.checked_mul(60 * 60).map(Duration::from_secs).ok_or_else(|| format!("Duration too long: '{}'", value)),
+        _ => Err(format!("Invalid duration unit: '{}'", value)),
+    }
+}
+
+fn validate_duration(value: String) -> Result<(), String> {
+    parse_duration(&value).map(|_| ())
+}
+
+/// Repeating something every zero seconds would never pause, so intervals must be positive.
+fn validate_interval(value: String) -> Result<(), String> {
+    match parse_duration(&value)? {
+        interval if interval > Duration::from_secs(0) => Ok(()),
+        _ => Err(String::from("Interval must be positive")),
+    }
+}
+
+/// Keeping no archives at all would prune the one just written, so retention counts must be positive.
+fn validate_retention(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(()),
+        _ => Err(String::from("Retention must be a positive number")),
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use clap::ErrorKind;
 
     use args_parser_config;
-    use arguments::{EXCLUDED_ENV, FILE_DEFAULT, FILE_ENV, SERVERS_DEFAULT, SERVERS_ENV, ZNODES_DEFAULT, ZNODES_ENV};
+    use arguments::{parse_duration, BACKUP_BEFORE_ENV, CHROOT_ENV, PARALLELISM_DEFAULT, PARALLELISM_ENV, LOG_FORMAT_ENV, ON_EPHEMERAL_ENV, PROTECTED_ENV, REPORT_ENV, EPHEMERAL_WAIT_DEFAULT, COMPACT_EVERY_DEFAULT, TLS_CA_ENV, TLS_CERT_ENV, TLS_KEY_ENV, CONNECT_TIMEOUT_DEFAULT, CONNECT_TIMEOUT_ENV, EXCLUDED_ENV, FILE_DEFAULT, JOURNAL_ENV, RETRIES_ENV,
+                    RETRY_BACKOFF_ENV, SEQUENTIAL_ENV, SESSION_TIMEOUT_DEFAULT, SESSION_TIMEOUT_ENV, FILE_ENV, SERVERS_DEFAULT, SERVERS_ENV, ZNODES_DEFAULT, ZNODES_ENV};
 
     #[serial]
     #[test]
@@ -123,6 +493,17 @@ mod tests {
         assert!(!parsed.is_present("restore"));
     }
 
+    #[serial]
+    #[test]
+    pub fn one_flag_rollback() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--rollback", "journal.log"].iter());
+        assert_eq!(parsed.value_of("rollback").unwrap(), "journal.log");
+        assert!(!parsed.is_present("dump"));
+        assert!(!parsed.is_present("restore"));
+        assert!(!parsed.is_present("delete"));
+    }
+
     #[serial]
     #[test]
     fn when_no_flags_then_error() {
@@ -171,7 +552,7 @@ mod tests {
     #[serial]
     #[test]
     fn servers() {
-        std::env::set_var(SERVERS_ENV, "not_expected".to_string());
+        std::env::set_var(SERVERS_ENV, "not_expected");
 
         let parser = args_parser_config();
         let parsed = parser.get_matches_from(["zk-loader", "-d", "-s=8.8.8.8"].iter());
@@ -243,7 +624,7 @@ mod tests {
     #[serial]
     #[test]
     fn file() {
-        std::env::set_var(FILE_ENV, "not_expected".to_string());
+        std::env::set_var(FILE_ENV, "not_expected");
 
         let parser = args_parser_config();
         let parsed = parser.get_matches_from(["zk-loader", "-d", "-f=save/file.tar.gz"].iter());
@@ -300,4 +681,459 @@ mod tests {
         let excluded: Vec<&str> = excluded.unwrap().collect();
         assert_eq!(excluded, ["/excluded1", "/excluded2"])
     }
+
+    #[serial]
+    #[test]
+    fn check_versions() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--check-versions"].iter());
+        assert!(parsed.is_present("check-versions"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete"].iter());
+        assert!(!parsed.is_present("check-versions"));
+    }
+
+    #[serial]
+    #[test]
+    fn when_dump_and_check_versions_then_error() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--check-versions"].iter());
+        let error_kind = parsed.unwrap_err().kind;
+        assert_eq!(error_kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn backup_before() {
+        std::env::set_var(BACKUP_BEFORE_ENV, "not_expected");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete", "--backup-before=backup.tar.gz"].iter());
+        let backup_file = parsed.value_of("backup-before");
+        assert_eq!(backup_file.unwrap(), "backup.tar.gz");
+        std::env::remove_var(BACKUP_BEFORE_ENV);
+    }
+
+    #[serial]
+    #[test]
+    fn backup_before_env() {
+        let expected_val = "expected".to_string();
+        std::env::set_var(BACKUP_BEFORE_ENV, &expected_val);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r"].iter());
+        let backup_file = parsed.value_of("backup-before").unwrap();
+        assert_eq!(backup_file, expected_val);
+        std::env::remove_var(BACKUP_BEFORE_ENV);
+    }
+
+    #[serial]
+    #[test]
+    fn when_dump_and_backup_before_then_error() {
+        std::env::remove_var(BACKUP_BEFORE_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--backup-before=backup.tar.gz"].iter());
+        let error_kind = parsed.unwrap_err().kind;
+        assert_eq!(error_kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn journal() {
+        std::env::set_var(JOURNAL_ENV, "not_expected");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--journal=journal.log"].iter());
+        let journal = parsed.value_of("journal");
+        assert_eq!(journal.unwrap(), "journal.log");
+        std::env::remove_var(JOURNAL_ENV);
+    }
+
+    #[serial]
+    #[test]
+    fn journal_env() {
+        let expected_val = "expected".to_string();
+        std::env::set_var(JOURNAL_ENV, &expected_val);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete"].iter());
+        let journal = parsed.value_of("journal").unwrap();
+        assert_eq!(journal, expected_val);
+        std::env::remove_var(JOURNAL_ENV);
+    }
+
+    #[serial]
+    #[test]
+    fn when_rollback_and_restore_then_error() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--rollback", "journal.log"].iter());
+        let error_kind = parsed.unwrap_err().kind;
+        assert_eq!(error_kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn sequential() {
+        std::env::remove_var(SEQUENTIAL_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--sequential=recreate"].iter());
+        assert_eq!(parsed.value_of("sequential").unwrap(), "recreate");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--sequential=recreate", "--sequential-parents", "/locks,/queue"].iter());
+        assert_eq!(parsed.values_of("sequential-parents").unwrap().collect::<Vec<&str>>(), ["/locks", "/queue"]);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--sequential-parents", "/locks"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::MissingRequiredArgument);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r"].iter());
+        assert!(parsed.value_of("sequential").is_none());
+    }
+
+    #[serial]
+    #[test]
+    fn sequential_env() {
+        std::env::set_var(SEQUENTIAL_ENV, "recreate");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r"].iter());
+        assert_eq!(parsed.value_of("sequential").unwrap(), "recreate");
+        std::env::remove_var(SEQUENTIAL_ENV);
+    }
+
+    #[serial]
+    #[test]
+    fn when_unknown_sequential_policy_then_error() {
+        std::env::remove_var(SEQUENTIAL_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--sequential=banana"].iter());
+        let error_kind = parsed.unwrap_err().kind;
+        assert_eq!(error_kind, ErrorKind::InvalidValue)
+    }
+
+    #[serial]
+    #[test]
+    fn include_ephemeral() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--include-ephemeral"].iter());
+        assert!(parsed.is_present("include-ephemeral"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--include-ephemeral"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn incremental_from() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--incremental-from", "base.tar.gz"].iter());
+        assert_eq!(parsed.value_of("incremental-from"), Some("base.tar.gz"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--incremental-from", "base.tar.gz"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn watch() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--watch", "--change-log", "changes.log"].iter());
+        assert!(parsed.is_present("watch"));
+        assert_eq!(parsed.value_of("change-log"), Some("changes.log"));
+        assert_eq!(parsed.value_of("compact-every"), Some(COMPACT_EVERY_DEFAULT));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--watch", "--compact-every", "0s"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--watch"].iter());
+        assert!(parsed.is_err());
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--watch", "--incremental-from", "base.tar.gz"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn every() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--every", "1h", "--keep-last", "24", "--keep-daily", "7"].iter());
+        assert_eq!(parsed.value_of("every"), Some("1h"));
+        assert_eq!(parsed.value_of("keep-last"), Some("24"));
+        assert_eq!(parsed.value_of("keep-daily"), Some("7"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--keep-last", "24"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::MissingRequiredArgument);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--every", "often"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--every", "0"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--every", "1h", "--keep-last", "0"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation)
+    }
+
+    #[serial]
+    #[test]
+    fn list_ephemeral() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--list-ephemeral"].iter());
+        assert!(parsed.is_present("list-ephemeral"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--list-ephemeral"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[test]
+    fn parse_duration_test() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("15s"), Ok(Duration::from_secs(15)));
+        assert_eq!(parse_duration("15"), Ok(Duration::from_secs(15)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("15d").is_err());
+        assert!(parse_duration("18446744073709551615m").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+    }
+
+    #[serial]
+    #[test]
+    fn timeouts_default() {
+        std::env::remove_var(SESSION_TIMEOUT_ENV);
+        std::env::remove_var(CONNECT_TIMEOUT_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d"].iter());
+        assert_eq!(parsed.value_of("session-timeout").unwrap(), SESSION_TIMEOUT_DEFAULT);
+        assert_eq!(parsed.value_of("connect-timeout").unwrap(), CONNECT_TIMEOUT_DEFAULT);
+    }
+
+    #[serial]
+    #[test]
+    fn session_timeout_env() {
+        std::env::set_var(SESSION_TIMEOUT_ENV, "1m");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d"].iter());
+        assert_eq!(parsed.value_of("session-timeout").unwrap(), "1m");
+        std::env::remove_var(SESSION_TIMEOUT_ENV);
+    }
+
+    #[serial]
+    #[test]
+    fn when_invalid_timeout_then_error() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--connect-timeout=soon"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation)
+    }
+
+    #[serial]
+    #[test]
+    fn retries() {
+        std::env::remove_var(RETRIES_ENV);
+        std::env::remove_var(RETRY_BACKOFF_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--retries=5", "--retry-backoff=1s"].iter());
+        assert_eq!(parsed.value_of("retries").unwrap(), "5");
+        assert_eq!(parsed.value_of("retry-backoff").unwrap(), "1s");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--retries=-1"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation)
+    }
+
+    #[serial]
+    #[test]
+    fn chroot() {
+        std::env::remove_var(CHROOT_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "--chroot=/kafka-b"].iter());
+        assert_eq!(parsed.value_of("chroot").unwrap(), "/kafka-b");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-r", "--chroot=kafka-b"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation)
+    }
+
+    #[serial]
+    #[test]
+    fn tls() {
+        std::env::remove_var(TLS_CA_ENV);
+        std::env::remove_var(TLS_KEY_ENV);
+        std::env::set_var(TLS_CERT_ENV, "client.pem");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--tls-ca=ca.pem", "--tls-key=client.key"].iter());
+        assert_eq!(parsed.value_of("tls-ca").unwrap(), "ca.pem");
+        assert_eq!(parsed.value_of("tls-cert").unwrap(), "client.pem");
+        assert_eq!(parsed.value_of("tls-key").unwrap(), "client.key");
+        std::env::remove_var(TLS_CERT_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--tls"].iter());
+        assert!(parsed.is_present("tls"));
+        assert!(!parsed.is_present("tls-cert"));
+    }
+
+    #[serial]
+    #[test]
+    fn when_tls_cert_without_key_then_error() {
+        std::env::remove_var(TLS_CERT_ENV);
+        std::env::remove_var(TLS_KEY_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--tls-cert=client.pem"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::MissingRequiredArgument)
+    }
+
+    #[serial]
+    #[test]
+    fn parallelism() {
+        std::env::remove_var(PARALLELISM_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d"].iter());
+        assert_eq!(parsed.value_of("parallelism").unwrap(), PARALLELISM_DEFAULT);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--parallelism=32"].iter());
+        assert_eq!(parsed.value_of("parallelism").unwrap(), "32");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--parallelism=0"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ValueValidation)
+    }
+
+    #[serial]
+    #[test]
+    fn quiet() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d"].iter());
+        assert!(!parsed.is_present("quiet"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-r", "-q"].iter());
+        assert!(parsed.is_present("quiet"));
+    }
+
+    #[serial]
+    #[test]
+    fn logging() {
+        std::env::remove_var(LOG_FORMAT_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d"].iter());
+        assert_eq!(parsed.occurrences_of("verbose"), 0);
+        assert_eq!(parsed.value_of("log-format").unwrap(), "text");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "-vv", "--log-format=json"].iter());
+        assert_eq!(parsed.occurrences_of("verbose"), 2);
+        assert_eq!(parsed.value_of("log-format").unwrap(), "json");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--log-format=xml"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::InvalidValue)
+    }
+
+    #[serial]
+    #[test]
+    fn report() {
+        std::env::remove_var(REPORT_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d"].iter());
+        assert!(!parsed.is_present("report"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete", "--report=report.json"].iter());
+        assert_eq!(parsed.value_of("report").unwrap(), "report.json");
+    }
+
+    #[serial]
+    #[test]
+    fn failure_policy() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "-d", "--keep-going"].iter());
+        assert!(parsed.is_present("keep-going"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--keep-going", "--fail-fast"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn require_existing() {
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete", "-z", "/a,/b", "--require-existing"].iter());
+        assert!(parsed.is_present("require-existing"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--require-existing"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
+
+    #[serial]
+    #[test]
+    fn on_ephemeral() {
+        std::env::remove_var(ON_EPHEMERAL_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete"].iter());
+        assert!(!parsed.is_present("on-ephemeral"));
+        assert_eq!(parsed.value_of("ephemeral-wait").unwrap(), EPHEMERAL_WAIT_DEFAULT);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete", "--on-ephemeral", "wait", "--ephemeral-wait", "30s"].iter());
+        assert_eq!(parsed.value_of("on-ephemeral").unwrap(), "wait");
+        assert_eq!(parsed.value_of("ephemeral-wait").unwrap(), "30s");
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "--delete", "--on-ephemeral", "ignore"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::InvalidValue)
+    }
+
+    #[serial]
+    #[test]
+    fn protected() {
+        std::env::remove_var(PROTECTED_ENV);
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete", "-y"].iter());
+        assert!(parsed.is_present("yes"));
+        assert!(!parsed.is_present("protected"));
+
+        std::env::set_var(PROTECTED_ENV, "/kafka,/hbase");
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from(["zk-loader", "--delete", "--allow-protected"].iter());
+        std::env::remove_var(PROTECTED_ENV);
+        assert_eq!(parsed.values_of("protected").unwrap().collect::<Vec<&str>>(), ["/kafka", "/hbase"]);
+        assert!(parsed.is_present("allow-protected"));
+
+        let parser = args_parser_config();
+        let parsed = parser.get_matches_from_safe(["zk-loader", "-d", "--yes"].iter());
+        assert_eq!(parsed.unwrap_err().kind, ErrorKind::ArgumentConflict)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+
+use archive::{format_zxid, parse_hex, ZnodeMeta};
+use journal::{from_hex, to_hex};
+
+const CHANGED: &str = "changed";
+const DELETED: &str = "deleted";
+const ZXID_KEY: &str = "zxid=";
+
+/// Change of a watched znode together with the state it left the znode in.
+/// A deletion carries the zxid its parent's children last changed at, if it was read.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Changed { path: String, meta: ZnodeMeta, data: Vec<u8> },
+    Deleted { path: String, zxid: Option<i64> },
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match *self {
+            Change::Changed { ref path, .. } | Change::Deleted { ref path, .. } => path,
+        }
+    }
+
+    /// Highest zxid the change is known to be applied at.
+    pub fn zxid(&self) -> Option<i64> {
+        match *self {
+            Change::Changed { ref meta, .. } => meta.mzxid.max(meta.pzxid),
+            Change::Deleted { zxid, .. } => zxid,
+        }
+    }
+}
+
+/// Append-only log of the changes seen since the archive was last compacted, one entry per line:
+/// `<kind>\t<metadata>\t<hex data>\t<path>`, metadata being comma separated `key=value`, only `zxid` for deletions.
+/// Every entry goes straight to the file, so no change is lost if the process is killed before compaction.
+pub struct ChangeLog {
+    file: File,
+}
+
+impl ChangeLog {
+    /// Starts an empty change log, dropping whatever the file held.
+    pub fn create(change_log_file: &str) -> Result<ChangeLog> {
+        let file = OpenOptions::new().create(true).append(true).open(change_log_file)?;
+        file.set_len(0)?;
+        Ok(ChangeLog { file })
+    }
+
+    pub fn record(&mut self, change: &Change) -> Result<()> {
+        writeln!(self.file, "{}", format_change(change))
+    }
+
+    /// Drops the entries once they are compacted into the archive.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)
+    }
+}
+
+pub fn read_change_log(change_log_file: &str) -> Result<Vec<Change>> {
+    let reader = BufReader::new(File::open(change_log_file)?);
+    let mut changes = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            changes.push(parse_change(&line)?);
+        }
+    }
+    Ok(changes)
+}
+
+fn format_change(change: &Change) -> String {
+    match *change {
+        Change::Changed { ref path, ref meta, ref data } => {
+            let meta = String::from_utf8_lossy(&meta.to_bytes()).trim_end().replace('\n', ",");
+            format!("{}\t{}\t{}\t{}", CHANGED, meta, to_hex(data), path)
+        }
+        Change::Deleted { ref path, zxid } => {
+            let meta = zxid.map(|zxid| String::from(ZXID_KEY) + &format_zxid(zxid)).unwrap_or_default();
+            format!("{}\t{}\t\t{}", DELETED, meta, path)
+        }
+    }
+}
+
+fn parse_change(line: &str) -> Result<Change> {
+    let fields: Vec<&str> = line.splitn(4, '\t').collect();
+    if fields.len() != 4 {
+        return Err(invalid_data(line));
+    }
+    let path = String::from(fields[3]);
+    match fields[0] {
+        CHANGED => Ok(Change::Changed { path, meta: ZnodeMeta::parse(fields[1].replace(',', "\n").as_bytes())?, data: from_hex(fields[2])? }),
+        DELETED => {
+            let zxid = match fields[1] {
+                "" => None,
+                meta => Some(parse_hex(line, meta.strip_prefix(ZXID_KEY).ok_or_else(|| invalid_data(line))?)?),
+            };
+            Ok(Change::Deleted { path, zxid })
+        }
+        _ => Err(invalid_data(line)),
+    }
+}
+
+fn invalid_data(content: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Malformed change log content: '{}'", content))
+}
+
+#[cfg(test)]
+mod tests {
+    use archive::{ZnodeMeta, ZnodeMode};
+    use change_log::{format_change, parse_change, Change};
+
+    #[test]
+    pub fn change_round_trip() {
+        let changes = vec![
+            Change::Changed {
+                path: String::from("/banana/split"),
+                meta: ZnodeMeta { mode: ZnodeMode::PersistentSequential, mzxid: Some(0x1_0000_002a), pzxid: Some(0x1_0000_0010), ..Default::default() },
+                data: b"123data!".to_vec(),
+            },
+            Change::Deleted { path: String::from("/banana/empty"), zxid: Some(0x1_0000_0030) },
+            Change::Deleted { path: String::from("/banana/old"), zxid: None },
+        ];
+        for change in changes {
+            assert_eq!(parse_change(&format_change(&change)).unwrap(), change);
+        }
+    }
+
+    #[test]
+    pub fn change_format() {
+        let change = Change::Changed { path: String::from("/banana"), meta: ZnodeMeta { mzxid: Some(0x2a), ..Default::default() }, data: vec![0x01, 0xab] };
+        assert_eq!(format_change(&change), "changed\tmode=persistent,mzxid=0x2a\t01ab\t/banana");
+        assert!(parse_change("changed\tmode=moved\t\t/banana").is_err());
+        assert!(parse_change("moved\t\t\t/banana").is_err());
+        let deletion = Change::Deleted { path: String::from("/banana"), zxid: Some(0x2a) };
+        assert_eq!(format_change(&deletion), "deleted\tzxid=0x2a\t\t/banana");
+        assert!(parse_change("deleted\tmzxid=0x2a\t\t/banana").is_err());
+    }
+}
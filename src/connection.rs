@@ -0,0 +1,386 @@
+use std::cmp;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zookeeper::{Acl, CreateMode, KeeperState, Stat, WatchedEvent, Watcher, ZkError, ZkResult, ZkState, ZooKeeper};
+
+use tls::{start_tunnels, TlsSettings, Tunnels};
+
+/// Longest pause between two attempts of an operation.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Where and how to connect to zookeeper.
+#[derive(Clone, Debug)]
+pub struct ConnectionSettings {
+    pub servers: String,
+    /// Znode all paths are relative to
+    pub chroot: Option<String>,
+    pub session_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub retry_policy: RetryPolicy,
+    /// Connect to the secure client port
+    pub tls: Option<TlsSettings>,
+}
+
+impl ConnectionSettings {
+    /// Along with the tunnels the connection goes through when using TLS, which have to live as long as it.
+    fn connection_string(&self) -> (String, Option<Tunnels>) {
+        let (servers, tunnels) = match self.tls {
+            Some(ref tls) => {
+                let tunnels = start_tunnels(&self.servers, tls, self.session_timeout)
+                    .unwrap_or_else(|err| panic!("Can't set up TLS connection to '{}'. Reason: '{}'", self.servers, err));
+                (tunnels.servers(), Some(tunnels))
+            }
+            None => (self.servers.clone(), None),
+        };
+        (servers + self.chroot.as_deref().unwrap_or(""), tunnels)
+    }
+}
+
+/// Splits a connection string like `zk1:2181,zk2:2181/kafka` into the servers and the chroot.
+pub fn split_chroot(connection_string: &str) -> (&str, Option<String>) {
+    match connection_string.find('/') {
+        Some(index) => (&connection_string[..index], normalize_chroot(&connection_string[index..])),
+        None => (connection_string, None),
+    }
+}
+
+/// Drops trailing slashes of a chroot, `/` meaning no chroot at all.
+pub fn normalize_chroot(chroot: &str) -> Option<String> {
+    let chroot = chroot.trim_end_matches('/');
+    if chroot.is_empty() {
+        None
+    } else {
+        Some(String::from(chroot))
+    }
+}
+
+/// Repeats operations failed because of a transient error, pausing twice as long after every attempt.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy { max_retries: 3, initial_backoff: Duration::from_millis(100) }
+    }
+}
+
+impl RetryPolicy {
+    pub fn retry<T, F>(&self, mut operation: F) -> ZkResult<T> where F: FnMut() -> ZkResult<T> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match operation() {
+                Err(ref err) if is_transient(err) && retries < self.max_retries => {
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Retries an operation which may have been applied although its reply was lost. Instead of issuing it again
+    /// right away, `applied` tells from the current state whether it was: with the result of the operation if so,
+    /// with nothing if it wasn't, so that the operation is issued again.
+    pub fn retry_unless_applied<T, F, A>(&self, mut operation: F, mut applied: A) -> ZkResult<T>
+        where F: FnMut() -> ZkResult<T>, A: FnMut() -> ZkResult<Option<T>> {
+        let mut reply_lost = false;
+        self.retry(|| {
+            if reply_lost {
+                if let Some(result) = applied()? {
+                    return Ok(result);
+                }
+            }
+            let result = operation();
+            reply_lost = result.as_ref().err().is_some_and(is_transient);
+            result
+        })
+    }
+}
+
+/// Errors after which the same operation may succeed once the client reconnects.
+fn is_transient(err: &ZkError) -> bool {
+    matches!(*err, ZkError::ConnectionLoss | ZkError::OperationTimeout)
+}
+
+/// What connection state events tell about the session.
+#[derive(Debug, Default)]
+struct SessionState {
+    lost: bool,
+    disconnected_since: Option<Instant>,
+}
+
+impl SessionState {
+    fn on_keeper_state(&mut self, state: KeeperState) {
+        match state {
+            KeeperState::Expired | KeeperState::AuthFailed => self.lost = true,
+            KeeperState::Disconnected => self.on_disconnect(),
+            KeeperState::SyncConnected | KeeperState::ConnectedReadOnly => self.disconnected_since = None,
+            KeeperState::SaslAuthenticated => {}
+        }
+    }
+
+    fn on_state(&mut self, state: ZkState) {
+        match state {
+            ZkState::Closed | ZkState::AuthFailed => self.lost = true,
+            ZkState::Connected | ZkState::ConnectedReadOnly => self.disconnected_since = None,
+            _ => self.on_disconnect(),
+        }
+    }
+
+    fn on_disconnect(&mut self) {
+        if self.disconnected_since.is_none() {
+            self.disconnected_since = Some(Instant::now());
+        }
+    }
+
+    /// The session is lost once it expired, or the client was disconnected for longer than
+    /// the session timeout, so the server has expired it even if the client wasn't told yet.
+    fn is_lost(&mut self, session_timeout: Duration) -> bool {
+        if self.disconnected_since.is_some_and(|since| since.elapsed() > session_timeout) {
+            self.lost = true;
+        }
+        self.lost
+    }
+}
+
+/// Zookeeper client retrying every operation according to the retry policy.
+/// Creation of sequential znodes is never retried, as it may have succeeded despite the error. Other creations
+/// and versioned updates are only issued again once a re-read tells they weren't applied. Nothing tells a znode
+/// created or deleted by a lost attempt from someone else's doing, so a retry finding the znode there, or gone,
+/// fails with `ZkError::NodeExists`, or `ZkError::NoNode`, for the caller to treat it as a conflict.
+/// Once the session is lost every operation fails with `ZkError::SessionExpired`.
+pub struct ZkClient {
+    zk: Arc<ZooKeeper>,
+    retry_policy: RetryPolicy,
+    chroot: Option<String>,
+    session: Arc<Mutex<SessionState>>,
+    session_timeout: Duration,
+    /// Closed after the session, as fields are dropped in order
+    _tunnels: Option<Tunnels>,
+}
+
+impl ZkClient {
+    /// Connects to zookeeper, panicking if the connection can't be established within the connect timeout.
+    pub fn connect(settings: &ConnectionSettings) -> ZkClient {
+        let session = Arc::new(Mutex::new(SessionState::default()));
+        let watched_session = session.clone();
+        let watcher = move |event: WatchedEvent| watched_session.lock().unwrap().on_keeper_state(event.keeper_state);
+        let (connection_string, tunnels) = settings.connection_string();
+        let zk = ZooKeeper::connect(connection_string.as_str(), settings.session_timeout, watcher)
+            .unwrap_or_else(|err| panic!("Connection failed. Reason: '{}'", err));
+        let listened_session = session.clone();
+        zk.add_listener(move |state| listened_session.lock().unwrap().on_state(state));
+        let zk = Arc::new(zk);
+        let (sender, receiver) = mpsc::channel();
+        let probe = zk.clone();
+        thread::spawn(move || sender.send(probe.exists("/", false)));
+        match receiver.recv_timeout(settings.connect_timeout) {
+            Ok(Ok(_)) => ZkClient {
+                zk,
+                retry_policy: settings.retry_policy,
+                chroot: settings.chroot.clone(),
+                session,
+                session_timeout: settings.session_timeout,
+                _tunnels: tunnels,
+            },
+            Ok(Err(err)) => panic!("Connection failed. Reason: '{}'", err),
+            Err(_) => panic!("Connection to '{}' wasn't established within {:?}", settings.servers, settings.connect_timeout),
+        }
+    }
+
+    pub fn chroot(&self) -> Option<&str> {
+        self.chroot.as_deref()
+    }
+
+    /// Fails with `ZkError::SessionExpired` once the session is lost, along with every watch set in it.
+    pub fn check_session(&self) -> ZkResult<()> {
+        if self.session.lock().unwrap().is_lost(self.session_timeout) {
+            Err(ZkError::SessionExpired)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn retry<T, F>(&self, mut operation: F) -> ZkResult<T> where F: FnMut() -> ZkResult<T> {
+        self.retry_policy.retry(|| {
+            self.check_session()?;
+            operation()
+        })
+    }
+
+    pub fn exists(&self, path: &str, watch: bool) -> ZkResult<Option<Stat>> {
+        self.retry(|| self.zk.exists(path, watch))
+    }
+
+    pub fn get_data(&self, path: &str, watch: bool) -> ZkResult<(Vec<u8>, Stat)> {
+        self.retry(|| self.zk.get_data(path, watch))
+    }
+
+    pub fn get_children(&self, path: &str, watch: bool) -> ZkResult<Vec<String>> {
+        self.retry(|| self.zk.get_children(path, watch))
+    }
+
+    /// Reads the data setting a one-off `watcher` of its change or of the znode deletion.
+    pub fn get_data_w<W: Watcher + Clone + 'static>(&self, path: &str, watcher: W) -> ZkResult<(Vec<u8>, Stat)> {
+        self.retry(|| self.zk.get_data_w(path, watcher.clone()))
+    }
+
+    /// Lists the children setting a one-off `watcher` of their change or of the znode deletion.
+    pub fn get_children_w<W: Watcher + Clone + 'static>(&self, path: &str, watcher: W) -> ZkResult<Vec<String>> {
+        self.retry(|| self.zk.get_children_w(path, watcher.clone()))
+    }
+
+    pub fn get_acl(&self, path: &str) -> ZkResult<(Vec<Acl>, Stat)> {
+        self.retry(|| self.zk.get_acl(path))
+    }
+
+    pub fn set_data(&self, path: &str, data: Vec<u8>, version: Option<i32>) -> ZkResult<Stat> {
+        let version = match version {
+            Some(version) => version,
+            None => return self.retry(|| self.zk.set_data(path, data.clone(), None)),
+        };
+        self.retry_policy.retry_unless_applied(|| {
+            self.check_session()?;
+            self.zk.set_data(path, data.clone(), Some(version))
+        }, || {
+            self.check_session()?;
+            match self.zk.get_data(path, false)? {
+                (current, stat) if stat.version == version + 1 && current == data => Ok(Some(stat)),
+                (_, stat) if stat.version == version => Ok(None),
+                _ => Err(ZkError::BadVersion),
+            }
+        })
+    }
+
+    pub fn set_acl(&self, path: &str, acl: Vec<Acl>, version: Option<i32>) -> ZkResult<Stat> {
+        self.retry(|| self.zk.set_acl(path, acl.clone(), version))
+    }
+
+    pub fn create(&self, path: &str, data: Vec<u8>, acl: Vec<Acl>, mode: CreateMode) -> ZkResult<String> {
+        match mode {
+            CreateMode::PersistentSequential | CreateMode::EphemeralSequential => {
+                self.check_session()?;
+                self.zk.create(path, data, acl, mode)
+            }
+            _ => self.retry_policy.retry_unless_applied(|| {
+                self.check_session()?;
+                self.zk.create(path, data.clone(), acl.clone(), mode)
+            }, || {
+                self.check_session()?;
+                match self.zk.exists(path, false)? {
+                    Some(_) => Err(ZkError::NodeExists),
+                    None => Ok(None),
+                }
+            }),
+        }
+    }
+
+    pub fn delete(&self, path: &str, version: Option<i32>) -> ZkResult<()> {
+        self.retry(|| self.zk.delete(path, version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::thread;
+    use std::time::Duration;
+
+    use connection::{split_chroot, RetryPolicy, SessionState};
+    use zookeeper::{KeeperState, ZkError, ZkState};
+
+    #[test]
+    pub fn session_expiry() {
+        let mut session = SessionState::default();
+        session.on_state(ZkState::Connected);
+        assert!(!session.is_lost(Duration::from_secs(15)));
+        session.on_keeper_state(KeeperState::Expired);
+        assert!(session.is_lost(Duration::from_secs(15)));
+    }
+
+    #[test]
+    pub fn session_disconnected_longer_than_timeout() {
+        let mut session = SessionState::default();
+        session.on_keeper_state(KeeperState::Disconnected);
+        assert!(!session.is_lost(Duration::from_secs(15)));
+        session.on_state(ZkState::Connected);
+        session.on_state(ZkState::Connecting);
+        thread::sleep(Duration::from_millis(1));
+        assert!(session.is_lost(Duration::from_secs(0)));
+        session.on_state(ZkState::Connected);
+        assert!(session.is_lost(Duration::from_secs(15)));
+    }
+
+    #[test]
+    pub fn split_chroot_test() {
+        assert_eq!(split_chroot("zk1:2181,zk2:2181"), ("zk1:2181,zk2:2181", None));
+        assert_eq!(split_chroot("zk1:2181,zk2:2181/kafka"), ("zk1:2181,zk2:2181", Some(String::from("/kafka"))));
+        assert_eq!(split_chroot("zk1:2181/kafka/a/"), ("zk1:2181", Some(String::from("/kafka/a"))));
+        assert_eq!(split_chroot("zk1:2181/"), ("zk1:2181", None));
+    }
+
+    #[test]
+    pub fn retry_transient_errors() {
+        let policy = RetryPolicy { max_retries: 3, initial_backoff: Duration::from_millis(1) };
+        let attempts = Cell::new(0);
+        let result = policy.retry(|| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 { Err(ZkError::ConnectionLoss) } else { Ok(attempts.get()) }
+        });
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    pub fn check_before_issuing_again() {
+        let policy = RetryPolicy { max_retries: 3, initial_backoff: Duration::from_millis(1) };
+        let attempts = Cell::new(0);
+        let result = policy.retry_unless_applied(|| {
+            attempts.set(attempts.get() + 1);
+            Err(ZkError::ConnectionLoss)
+        }, || Ok(Some(42)));
+        assert_eq!(result, Ok(42));
+        assert_eq!(attempts.get(), 1);
+
+        let attempts = Cell::new(0);
+        let result = policy.retry_unless_applied(|| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 2 { Err(ZkError::ConnectionLoss) } else { Ok(attempts.get()) }
+        }, || Ok(None));
+        assert_eq!(result, Ok(2));
+
+        let result: Result<(), ZkError> = policy.retry_unless_applied(|| Err(ZkError::ConnectionLoss), || Err(ZkError::BadVersion));
+        assert_eq!(result, Err(ZkError::BadVersion));
+    }
+
+    #[test]
+    pub fn give_up_after_max_retries() {
+        let policy = RetryPolicy { max_retries: 2, initial_backoff: Duration::from_millis(1) };
+        let attempts = Cell::new(0);
+        let result: Result<(), ZkError> = policy.retry(|| {
+            attempts.set(attempts.get() + 1);
+            Err(ZkError::OperationTimeout)
+        });
+        assert_eq!(result, Err(ZkError::OperationTimeout));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    pub fn do_not_retry_other_errors() {
+        let policy = RetryPolicy { max_retries: 2, initial_backoff: Duration::from_millis(1) };
+        let attempts = Cell::new(0);
+        let result: Result<(), ZkError> = policy.retry(|| {
+            attempts.set(attempts.get() + 1);
+            Err(ZkError::NoNode)
+        });
+        assert_eq!(result, Err(ZkError::NoNode));
+        assert_eq!(attempts.get(), 1);
+    }
+}
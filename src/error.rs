@@ -0,0 +1,39 @@
+use std::fmt;
+use std::io;
+use std::result;
+
+use zookeeper::ZkError;
+
+/// Failure of an operation that must not be turned into a panic right away,
+/// e.g. so that the caller can abort before touching any znode.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Zk(ZkError),
+    /// The operation stopped on a failure recorded in its outcome
+    Stopped,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::Zk(ref err) => write!(f, "Zookeeper error: {}", err),
+            Error::Stopped => write!(f, "Stopped on a failed znode"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<ZkError> for Error {
+    fn from(err: ZkError) -> Error {
+        Error::Zk(err)
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Whether `znode_path` is `ancestor` itself or lies beneath it.
+pub fn is_within(znode_path: &str, ancestor: &str) -> bool {
+    let ancestor = ancestor.trim_end_matches('/');
+    ancestor.is_empty() || znode_path == ancestor || znode_path.starts_with(&format!("{}/", ancestor))
+}
+
+/// Pairs of a root to change and a protected znode it would touch. A protected znode guards its whole
+/// subtree, so it is touched by roots inside that subtree and by roots above it unless it is excluded.
+pub fn protected_violations<'a>(znode_paths: &[&'a str], excluded_znodes: &[&str], protected_znodes: &'a [String]) -> Vec<(&'a str, &'a str)> {
+    let mut violations = Vec::new();
+    for root in znode_paths {
+        for protected in protected_znodes {
+            let is_excluded = excluded_znodes.iter().any(|excluded| is_within(protected, excluded));
+            if is_within(root, protected) || (is_within(protected, root) && !is_excluded) {
+                violations.push((*root, protected.as_str()));
+            }
+        }
+    }
+    violations
+}
+
+/// Asks on the terminal whether to go on, which is only the case if the answer is yes.
+/// Without a terminal to ask on the answer is no.
+pub fn confirm(question: &str) -> bool {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        eprintln!("{} There is no terminal to confirm it on, pass --yes to go on anyway", question);
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    let _ = stdin.lock().read_line(&mut answer);
+    is_yes(&answer)
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use guard::{is_within, is_yes, protected_violations};
+
+    #[test]
+    pub fn is_within_test() {
+        assert!(is_within("/kafka", "/kafka"));
+        assert!(is_within("/kafka/brokers", "/kafka"));
+        assert!(is_within("/kafka/brokers", "/kafka/"));
+        assert!(is_within("/kafka", "/"));
+        assert!(!is_within("/kafka-old", "/kafka"));
+        assert!(!is_within("/", "/kafka"));
+    }
+
+    #[test]
+    pub fn protected_violations_test() {
+        let protected = vec![String::from("/kafka/brokers"), String::from("/hbase")];
+        assert_eq!(protected_violations(&["/"], &[], &protected), [("/", "/kafka/brokers"), ("/", "/hbase")]);
+        assert_eq!(protected_violations(&["/"], &["/kafka", "/hbase"], &protected), []);
+        assert_eq!(protected_violations(&["/kafka/brokers/ids"], &[], &protected), [("/kafka/brokers/ids", "/kafka/brokers")]);
+        assert_eq!(protected_violations(&["/kafka/config", "/hbase-old"], &[], &protected), []);
+    }
+
+    #[test]
+    pub fn is_yes_test() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" Yes\n"));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no\n"));
+    }
+}
@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::sync::Mutex;
+
+use zookeeper::{Acl, Permission};
+
+use archive::ZnodeMode;
+
+const CREATED: &str = "created";
+const UPDATED: &str = "updated";
+const DELETED: &str = "deleted";
+const CHROOT_CREATED: &str = "chroot-created";
+
+/// Change made to a znode together with everything needed to revert it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JournalEntry {
+    Created { path: String },
+    Updated { path: String, data: Vec<u8>, acl: Vec<Acl> },
+    /// Deleted znode along with the way it was created
+    Deleted { path: String, data: Vec<u8>, acl: Vec<Acl>, mode: ZnodeMode },
+    /// Znode created for a missing chroot, its path isn't relative to the chroot
+    ChrootCreated { path: String },
+}
+
+impl JournalEntry {
+    pub fn path(&self) -> &str {
+        match *self {
+            JournalEntry::Created { ref path } | JournalEntry::Updated { ref path, .. } | JournalEntry::Deleted { ref path, .. }
+            | JournalEntry::ChrootCreated { ref path } => path,
+        }
+    }
+}
+
+/// Append-only log of the changes made by `restore` and `delete`, one entry per line:
+/// `<kind>\t<mode>\t<acl>\t<hex data>\t<path>`, the mode is only recorded for deleted znodes. Every entry goes straight to the file, so the
+/// journal stays usable even if the run is interrupted. A disabled journal records nothing.
+/// Concurrent writers may share one journal, entries never interleave.
+pub struct Journal {
+    file: Option<Mutex<File>>,
+}
+
+impl Journal {
+    pub fn create(journal_file: &str) -> Result<Journal> {
+        Ok(Journal { file: Some(Mutex::new(File::create(journal_file)?)) })
+    }
+
+    pub fn disabled() -> Journal {
+        Journal { file: None }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn record(&self, entry: &JournalEntry) -> Result<()> {
+        match self.file {
+            Some(ref file) => writeln!(file.lock().unwrap(), "{}", format_entry(entry)),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn read_journal(journal_file: &str) -> Result<Vec<JournalEntry>> {
+    let reader = BufReader::new(File::open(journal_file)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            entries.push(parse_entry(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+fn format_entry(entry: &JournalEntry) -> String {
+    match *entry {
+        JournalEntry::Created { ref path } => format!("{}\t\t\t\t{}", CREATED, path),
+        JournalEntry::Updated { ref path, ref data, ref acl } => format!("{}\t\t{}\t{}\t{}", UPDATED, format_acl(acl), to_hex(data), path),
+        JournalEntry::Deleted { ref path, ref data, ref acl, mode } =>
+            format!("{}\t{}\t{}\t{}\t{}", DELETED, mode.name(), format_acl(acl), to_hex(data), path),
+        JournalEntry::ChrootCreated { ref path } => format!("{}\t\t\t\t{}", CHROOT_CREATED, path),
+    }
+}
+
+fn parse_entry(line: &str) -> Result<JournalEntry> {
+    let fields: Vec<&str> = line.splitn(5, '\t').collect();
+    if fields.len() != 5 {
+        return Err(invalid_data(line));
+    }
+    let path = String::from(fields[4]);
+    match fields[0] {
+        CREATED => Ok(JournalEntry::Created { path }),
+        UPDATED => Ok(JournalEntry::Updated { path, data: from_hex(fields[3])?, acl: parse_acl(fields[2])? }),
+        DELETED => {
+            let mode = ZnodeMode::from_name(fields[1]).ok_or_else(|| invalid_data(line))?;
+            Ok(JournalEntry::Deleted { path, data: from_hex(fields[3])?, acl: parse_acl(fields[2])?, mode })
+        }
+        CHROOT_CREATED => Ok(JournalEntry::ChrootCreated { path }),
+        _ => Err(invalid_data(line)),
+    }
+}
+
+const PERMISSIONS: [(char, Permission); 5] = [
+    ('r', Permission::READ),
+    ('w', Permission::WRITE),
+    ('c', Permission::CREATE),
+    ('d', Permission::DELETE),
+    ('a', Permission::ADMIN),
+];
+
+/// Formats ACL as comma separated `<perms>:<scheme>:<id>`, with permissions written like zkCli does.
+fn format_acl(acl: &[Acl]) -> String {
+    acl.iter()
+        .map(|acl| {
+            let perms: String = PERMISSIONS.iter()
+                .filter(|(_, permission)| acl.perms.can(*permission))
+                .map(|(letter, _)| *letter)
+                .collect();
+            format!("{}:{}:{}", perms, acl.scheme, acl.id)
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn parse_acl(acl: &str) -> Result<Vec<Acl>> {
+    if acl.is_empty() {
+        return Ok(Vec::new());
+    }
+    acl.split(',')
+        .map(|entry| {
+            let fields: Vec<&str> = entry.splitn(3, ':').collect();
+            if fields.len() != 3 {
+                return Err(invalid_data(entry));
+            }
+            let mut perms = Permission::NONE;
+            for letter in fields[0].chars() {
+                match PERMISSIONS.iter().find(|(known, _)| *known == letter) {
+                    Some((_, permission)) => perms = perms | *permission,
+                    None => return Err(invalid_data(entry)),
+                }
+            }
+            Ok(Acl { perms, scheme: String::from(fields[1]), id: String::from(fields[2]) })
+        })
+        .collect()
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return Err(invalid_data(hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            .ok_or_else(|| invalid_data(hex)))
+        .collect()
+}
+
+fn invalid_data(content: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Malformed journal content: '{}'", content))
+}
+
+#[cfg(test)]
+mod tests {
+    use archive::ZnodeMode;
+    use journal::{format_entry, parse_entry, JournalEntry};
+    use zookeeper::{Acl, Permission};
+
+    #[test]
+    pub fn entry_round_trip() {
+        let entries = vec![
+            JournalEntry::Created { path: String::from("/banana") },
+            JournalEntry::Updated {
+                path: String::from("/banana/split"),
+                data: b"123data!".to_vec(),
+                acl: vec![Acl { perms: Permission::READ | Permission::ADMIN, scheme: String::from("digest"), id: String::from("user:hash=") }],
+            },
+            JournalEntry::Deleted { path: String::from("/banana/empty"), data: vec![], acl: vec![], mode: ZnodeMode::Persistent },
+            JournalEntry::Deleted { path: String::from("/banana/box"), data: b"1".to_vec(), acl: vec![], mode: ZnodeMode::Container },
+            JournalEntry::ChrootCreated { path: String::from("/fruits") },
+        ];
+        for entry in entries {
+            assert_eq!(parse_entry(&format_entry(&entry)).unwrap(), entry);
+        }
+    }
+
+    #[test]
+    pub fn entry_format() {
+        let entry = JournalEntry::Updated {
+            path: String::from("/banana"),
+            data: vec![0x01, 0xab],
+            acl: vec![Acl { perms: Permission::ALL, scheme: String::from("world"), id: String::from("anyone") }],
+        };
+        assert_eq!(format_entry(&entry), "updated\t\trwcda:world:anyone\t01ab\t/banana");
+        let entry = JournalEntry::Deleted { path: String::from("/banana"), data: vec![0x01], acl: vec![], mode: ZnodeMode::Ephemeral };
+        assert_eq!(format_entry(&entry), "deleted\tephemeral\t\t01\t/banana");
+    }
+
+    #[test]
+    pub fn malformed_entry() {
+        assert!(parse_entry("created\t/banana").is_err());
+        assert!(parse_entry("moved\t\t\t\t/banana").is_err());
+        assert!(parse_entry("updated\t\tx:world:anyone\t\t/banana").is_err());
+        assert!(parse_entry("updated\t\t\t0g\t/banana").is_err());
+        assert!(parse_entry("deleted\tsticky\t\t\t/banana").is_err());
+    }
+}
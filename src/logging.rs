@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// How log records are written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `<time> <level> <message> key=value...`
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+struct Logger {
+    format: LogFormat,
+    level: LevelFilter,
+}
+
+/// Installs the logger: warnings only by default, znodes written and skipped with `verbosity` 1,
+/// every znode visited with `verbosity` 2 and more. Records of other crates are only shown from warnings up.
+pub fn init(verbosity: u64, format: LogFormat) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    log::set_boxed_logger(Box::new(Logger { format, level }))
+        .map(|_| log::set_max_level(level))
+        .expect("Logger is already installed");
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let is_own = metadata.target().starts_with(module_path!().split("::").next().unwrap_or_default());
+        metadata.level() <= self.level && (is_own || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", format_record(record, self.format, SystemTime::now()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn format_record(record: &Record, format: LogFormat, time: SystemTime) -> String {
+    let mut fields = Fields { format, line: String::new() };
+    let _ = record.key_values().visit(&mut fields);
+    match format {
+        LogFormat::Text => format!("{} {} {}{}", format_time(time), record.level(), record.args(), fields.line),
+        LogFormat::Json => format!("{{\"time\":{},\"level\":{},\"target\":{},\"message\":{}{}}}", json_string(&format_time(time)),
+                                   json_string(record.level().as_str()), json_string(record.target()), json_string(&record.args().to_string()),
+                                   fields.line),
+    }
+}
+
+/// Appends key-value pairs of a record in the log format.
+struct Fields {
+    format: LogFormat,
+    line: String,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let _ = match self.format {
+            LogFormat::Text => write!(self.line, " {}={}", key, value),
+            LogFormat::Json => match value.to_i64() {
+                Some(number) => write!(self.line, ",{}:{}", json_string(key.as_str()), number),
+                None => write!(self.line, ",{}:{}", json_string(key.as_str()), json_string(&value.to_string())),
+            },
+        };
+        Ok(())
+    }
+}
+
+/// JSON string literal of `value`.
+pub fn json_string(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(literal, "\\u{:04x}", c as u32);
+            }
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// RFC 3339 UTC time with milliseconds, like `2021-03-04T05:06:07.089Z`.
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60,
+            since_epoch.subsec_millis())
+}
+
+/// Gregorian date of a day counted from 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use log::{Level, Record};
+
+    use logging::{format_record, format_time, json_string, LogFormat};
+
+    #[test]
+    pub fn format_time_test() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_millis(1_614_834_367_089)), "2021-03-04T05:06:07.089Z");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    pub fn json_string_test() {
+        assert_eq!(json_string("/a"), "\"/a\"");
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+
+    #[test]
+    pub fn format_record_test() {
+        let time = UNIX_EPOCH + Duration::from_secs(1);
+        let fields = [("event", "created"), ("znode", "/a \"b\"")];
+        assert_eq!(format_record(&Record::builder().level(Level::Info).target("zk_loader::zk_interaction").args(format_args!("Znode restored"))
+                                     .key_values(&fields).build(), LogFormat::Text, time),
+                   "1970-01-01T00:00:01.000Z INFO Znode restored event=created znode=/a \"b\"");
+        assert_eq!(format_record(&Record::builder().level(Level::Warn).target("zk_loader::zk_interaction").args(format_args!("Znode restored"))
+                                     .key_values(&fields).build(), LogFormat::Json, time),
+                   "{\"time\":\"1970-01-01T00:00:01.000Z\",\"level\":\"WARN\",\"target\":\"zk_loader::zk_interaction\",\
+                    \"message\":\"Znode restored\",\"event\":\"created\",\"znode\":\"/a \\\"b\\\"\"}");
+    }
+}
@@ -2,39 +2,205 @@
 extern crate serial_test;
 extern crate clap;
 extern crate flate2;
+#[macro_use]
+extern crate log;
+extern crate native_tls;
+extern crate sha2;
 extern crate tar;
+extern crate zookeeper;
 
-use clap::Values;
+use std::process;
 
-use arguments::args_parser_config;
+use clap::{ArgMatches, ErrorKind, Values};
+
+use arguments::{args_parser_config, parse_duration};
+use connection::{normalize_chroot, split_chroot, ConnectionSettings, RetryPolicy};
+use logging::LogFormat;
+use report::Outcome;
+use schedule::Retention;
+use tls::TlsSettings;
+use zk_interaction::{DeleteOptions, DumpOptions, EphemeralPolicy, FailurePolicy, RestoreOptions, SequentialPolicy, WatchOptions};
 
 mod zk_interaction;
 mod arguments;
+mod archive;
+mod change_log;
+mod connection;
+mod error;
+mod guard;
+mod journal;
+mod logging;
+mod progress;
+mod report;
+mod schedule;
+mod tls;
+mod traversal;
 
 fn main() {
     let parser = args_parser_config();
     let args = parser.get_matches();
+    let log_format = match args.value_of("log-format") {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    logging::init(args.occurrences_of("verbose"), log_format);
+    let connection = connection_settings(&args);
     if args.is_present("dump") {
-        let servers = args.value_of("servers").unwrap();
         let znodes = args.values_of("znodes").unwrap().collect::<Vec<&str>>();
         let file = args.value_of("file").unwrap();
         let mut excluded = args.values_of("excluded").get_or_insert(Values::default()).collect::<Vec<&str>>();
         excluded.push("/zookeeper");
-        zk_interaction::dump(servers, znodes, file, excluded);
+        let options = DumpOptions {
+            include_ephemeral: args.is_present("include-ephemeral"),
+            base_file: args.value_of("incremental-from").map(String::from),
+            parallelism: args.value_of("parallelism").unwrap().parse().unwrap(),
+            quiet: args.is_present("quiet"),
+            report_file: args.value_of("report").map(String::from),
+            failure_policy: failure_policy(&args),
+        };
+        if args.is_present("watch") {
+            let watch_options = WatchOptions {
+                change_log_file: args.value_of("change-log").map(String::from).unwrap_or_else(|| format!("{}.changes", file)),
+                compact_interval: parse_duration(args.value_of("compact-every").unwrap()).unwrap(),
+            };
+            exit_on_errors(&zk_interaction::watch(&connection, znodes, file, excluded, options, watch_options));
+        } else if let Some(every) = args.value_of("every") {
+            if let Err(err) = schedule::validate_template(file) {
+                clap::Error::with_description(&err, ErrorKind::ValueValidation).exit();
+            }
+            let retention = Retention {
+                keep_last: args.value_of("keep-last").map(|keep_last| keep_last.parse().unwrap()),
+                keep_daily: args.value_of("keep-daily").map(|keep_daily| keep_daily.parse().unwrap()),
+            };
+            schedule::run_every(file, parse_duration(every).unwrap(), retention, |file| {
+                // Connects anew for every dump, so that a lost session only fails one of them
+                let outcome = zk_interaction::dump(&connection, znodes.clone(), file, excluded.clone(), options.clone());
+                outcome.errors().is_empty()
+            });
+        } else {
+            exit_on_errors(&zk_interaction::dump(&connection, znodes, file, excluded, options));
+        }
     } else if args.is_present("restore") {
-        let servers = args.value_of("servers").unwrap();
+        if args.value_of("sequential") == Some("recreate") && !args.is_present("sequential-parents") {
+            clap::Error::with_description("'--sequential=recreate' needs '--sequential-parents' to know which znodes are sequential",
+                                          ErrorKind::MissingRequiredArgument).exit();
+        }
         let znodes = args.values_of("znodes").unwrap().collect::<Vec<&str>>();
         let file = args.value_of("file").unwrap();
         let mut excluded = args.values_of("excluded").get_or_insert(Values::default()).collect::<Vec<&str>>();
         excluded.push("/zookeeper");
-        zk_interaction::restore(servers, file, znodes, excluded);
+        let options = RestoreOptions {
+            check_versions: args.is_present("check-versions"),
+            backup_file: args.value_of("backup-before").map(String::from),
+            journal_file: args.value_of("journal").map(String::from),
+            sequential_policy: match args.value_of("sequential") {
+                Some("recreate") => SequentialPolicy::Recreate,
+                _ => SequentialPolicy::Preserve,
+            },
+            sequential_parents: args.values_of("sequential-parents").map(|parents| parents.map(String::from).collect()).unwrap_or_default(),
+            list_ephemeral: args.is_present("list-ephemeral"),
+            parallelism: args.value_of("parallelism").unwrap().parse().unwrap(),
+            quiet: args.is_present("quiet"),
+            report_file: args.value_of("report").map(String::from),
+            failure_policy: failure_policy(&args),
+            confirm: !args.is_present("yes"),
+            protected_znodes: protected_znodes(&args),
+        };
+        exit_on_errors(&zk_interaction::restore(&connection, file, znodes, excluded, options));
     } else if args.is_present("delete") {
-        let servers = args.value_of("servers").unwrap();
         let znodes = args.values_of("znodes").unwrap().collect::<Vec<&str>>();
         let mut excluded = args.values_of("excluded").get_or_insert(Values::default()).collect::<Vec<&str>>();
         excluded.push("/zookeeper");
-        zk_interaction::delete(servers, znodes, excluded);
+        let options = DeleteOptions {
+            check_versions: args.is_present("check-versions"),
+            require_existing: args.is_present("require-existing"),
+            ephemeral_policy: match args.value_of("on-ephemeral") {
+                Some("skip") => EphemeralPolicy::SkipAncestors,
+                Some("wait") => EphemeralPolicy::Wait(parse_duration(args.value_of("ephemeral-wait").unwrap()).unwrap()),
+                _ => EphemeralPolicy::Abort,
+            },
+            backup_file: args.value_of("backup-before").map(String::from),
+            journal_file: args.value_of("journal").map(String::from),
+            quiet: args.is_present("quiet"),
+            report_file: args.value_of("report").map(String::from),
+            failure_policy: failure_policy(&args),
+            confirm: !args.is_present("yes"),
+            protected_znodes: protected_znodes(&args),
+        };
+        exit_on_errors(&zk_interaction::delete(&connection, znodes, excluded, options));
+    } else if args.is_present("rollback") {
+        let journal = args.value_of("rollback").unwrap();
+        exit_on_errors(&zk_interaction::rollback(&connection, journal));
+    } else {
+        panic!("Expected flag dump, restore, delete or rollback, but achieved unexpected state.")
+    }
+}
+
+fn connection_settings(args: &ArgMatches) -> ConnectionSettings {
+    let duration = |name| parse_duration(args.value_of(name).unwrap()).unwrap();
+    let (servers, servers_chroot) = split_chroot(args.value_of("servers").unwrap());
+    ConnectionSettings {
+        servers: String::from(servers),
+        chroot: args.value_of("chroot").map_or(servers_chroot, normalize_chroot),
+        session_timeout: duration("session-timeout"),
+        connect_timeout: duration("connect-timeout"),
+        retry_policy: RetryPolicy {
+            max_retries: args.value_of("retries").unwrap().parse().unwrap(),
+            initial_backoff: duration("retry-backoff"),
+        },
+        tls: tls_settings(args),
+    }
+}
+
+fn tls_settings(args: &ArgMatches) -> Option<TlsSettings> {
+    let is_enabled = ["tls", "tls-ca", "tls-cert", "tls-key"].iter().any(|name| args.is_present(name));
+    if !is_enabled {
+        return None;
+    }
+    Some(TlsSettings {
+        ca_file: args.value_of("tls-ca").map(String::from),
+        cert_file: args.value_of("tls-cert").map(String::from),
+        key_file: args.value_of("tls-key").map(String::from),
+    })
+}
+
+fn failure_policy(args: &ArgMatches) -> FailurePolicy {
+    if args.is_present("keep-going") {
+        FailurePolicy::KeepGoing
     } else {
-        panic!("Expected flag dump, restore or delete, but achieved unexpected state.")
+        FailurePolicy::FailFast
+    }
+}
+
+/// Znodes guarded against restore and delete, none if that is explicitly allowed.
+fn protected_znodes(args: &ArgMatches) -> Vec<String> {
+    match args.values_of("protected") {
+        Some(protected) if !args.is_present("allow-protected") => protected.map(String::from).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Lists the znodes the run failed on and exits with a non-zero code if there are any, if any were left
+/// untouched because of a version conflict or if the run wasn't confirmed.
+fn exit_on_errors(outcome: &Outcome) {
+    let errors = outcome.errors();
+    if !errors.is_empty() {
+        eprintln!("Failed on {} znodes:", errors.len());
+        for failure in errors {
+            eprintln!("  '{}': {}", failure.znode_path, failure.error);
+        }
+        if outcome.is_stopped() {
+            eprintln!("Stopped on the last one, the rest was left undone");
+        }
+    }
+    if outcome.is_aborted() {
+        eprintln!("Aborted, nothing was changed");
+    }
+    let conflicts = outcome.conflicts();
+    if !conflicts.is_empty() {
+        eprintln!("Left {} znodes modified since planning untouched", conflicts.len());
+    }
+    if outcome.exit_code() != 0 {
+        process::exit(outcome.exit_code());
     }
 }
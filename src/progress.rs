@@ -0,0 +1,155 @@
+use std::fmt;
+use std::ops::Add;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use log::LevelFilter;
+
+/// How often the progress line on stderr is refreshed.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// What an operation did with the znodes it came across.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub created: usize,
+    pub updated: usize,
+    pub dumped: usize,
+    /// Znodes an incremental dump found the same as in its base archive
+    pub unchanged: usize,
+    pub deleted: usize,
+    /// Ephemeral znodes
+    pub skipped: usize,
+    /// Excluded subtrees when dumping, excluded znodes of the archive when restoring
+    pub excluded: usize,
+    /// Root znodes to delete which didn't exist, znodes deleted while dumping before they were read
+    pub missing: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for Summary {
+    /// Lists the counts which aren't zero.
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let counts = [
+            (self.created, "created"),
+            (self.updated, "updated"),
+            (self.dumped, "dumped"),
+            (self.unchanged, "unchanged"),
+            (self.deleted, "deleted"),
+            (self.skipped, "skipped"),
+            (self.excluded, "excluded"),
+            (self.missing, "missing"),
+            (self.failed, "failed"),
+        ];
+        let counts: Vec<String> = counts.iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{} {}", count, name))
+            .collect();
+        write!(formatter, "{}", counts.join(", "))
+    }
+}
+
+impl Add for Summary {
+    type Output = Summary;
+
+    fn add(self, other: Summary) -> Summary {
+        Summary {
+            created: self.created + other.created,
+            updated: self.updated + other.updated,
+            dumped: self.dumped + other.dumped,
+            unchanged: self.unchanged + other.unchanged,
+            deleted: self.deleted + other.deleted,
+            skipped: self.skipped + other.skipped,
+            excluded: self.excluded + other.excluded,
+            missing: self.missing + other.missing,
+            failed: self.failed + other.failed,
+        }
+    }
+}
+
+/// Progress line of a long operation on stderr, refreshed at most once per `REPORT_INTERVAL`.
+/// It is left out when znodes are logged, as it would be mixed up with the log records.
+pub struct Progress {
+    quiet: bool,
+    started: Instant,
+    last_report: Option<Instant>,
+    znodes: usize,
+    bytes: usize,
+    /// How many znodes the operation is expected to come across, if it can be told
+    expected: Option<usize>,
+}
+
+impl Progress {
+    /// Reports nothing at all if `quiet`.
+    pub fn new(quiet: bool) -> Progress {
+        Progress { quiet, started: Instant::now(), last_report: None, znodes: 0, bytes: 0, expected: None }
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Lets the progress line tell how long the rest takes.
+    pub fn expect(&mut self, znodes: usize) {
+        self.expected = Some(znodes);
+    }
+
+    pub fn advance(&mut self, znodes: usize, bytes: usize) {
+        self.znodes += znodes;
+        self.bytes += bytes;
+        let is_due = self.last_report.is_none_or(|last_report| last_report.elapsed() >= REPORT_INTERVAL);
+        if !self.quiet && is_due && log::max_level() < LevelFilter::Info {
+            eprint!("\r{}", format_progress(self.znodes, self.bytes, self.started.elapsed(), self.expected));
+            let _ = io::stderr().flush();
+            self.last_report = Some(Instant::now());
+        }
+    }
+
+    /// Ends the progress line with a summary like `Restored 10 znodes, ...: 8 created, 2 updated`.
+    pub fn finish(&self, action: &str, summary: &Summary) {
+        if self.quiet {
+            return;
+        }
+        if self.last_report.is_some() {
+            eprintln!();
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let summary = summary.to_string();
+        eprintln!("{} {} znodes, {:.1} MiB in {:.1} s, {:.0} znodes/s{}{}", action, self.znodes, self.bytes as f64 / MIB, elapsed,
+                  self.znodes as f64 / elapsed.max(0.001), if summary.is_empty() { "" } else { ": " }, summary);
+    }
+}
+
+/// The ETA assumes the remaining znodes take as long as the ones so far, it is 0 once more than expected are done.
+fn format_progress(znodes: usize, bytes: usize, elapsed: Duration, expected: Option<usize>) -> String {
+    let elapsed = elapsed.as_secs_f64();
+    let mut line = format!("{} znodes, {:.1} MiB, {:.0} znodes/s", znodes, bytes as f64 / MIB, znodes as f64 / elapsed.max(0.001));
+    if let Some(expected) = expected.filter(|_| znodes > 0) {
+        let remaining = elapsed * expected.saturating_sub(znodes) as f64 / znodes as f64;
+        line.push_str(&format!(", ETA {:.0} s", remaining));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use progress::{format_progress, Summary};
+
+    #[test]
+    pub fn format_progress_test() {
+        let mib = 1024 * 1024;
+        assert_eq!(format_progress(100, 3 * mib, Duration::from_secs(2), None), "100 znodes, 3.0 MiB, 50 znodes/s");
+        assert_eq!(format_progress(100, 3 * mib, Duration::from_secs(2), Some(400)), "100 znodes, 3.0 MiB, 50 znodes/s, ETA 6 s");
+        assert_eq!(format_progress(500, 3 * mib, Duration::from_secs(2), Some(400)), "500 znodes, 3.0 MiB, 250 znodes/s, ETA 0 s");
+        assert_eq!(format_progress(0, 0, Duration::from_secs(2), Some(400)), "0 znodes, 0.0 MiB, 0 znodes/s");
+    }
+
+    #[test]
+    pub fn summary_lists_nonzero_counts() {
+        assert_eq!(Summary::default().to_string(), "");
+        let summary = Summary { created: 8, updated: 2, failed: 1, ..Default::default() };
+        assert_eq!(summary.to_string(), "8 created, 2 updated, 1 failed");
+    }
+}
@@ -0,0 +1,380 @@
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use guard::is_within;
+use logging::{format_time, json_string};
+use progress::Summary;
+
+/// A znode an operation failed on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub znode_path: String,
+    pub error: String,
+    /// Changed by someone else since planning and left untouched, which doesn't fail the run
+    pub conflict: bool,
+}
+
+/// Summaries of every root znode of an operation along with the znodes it failed on.
+#[derive(Clone, Debug, Default)]
+pub struct Outcome {
+    roots: Vec<(String, Summary)>,
+    failures: Vec<Failure>,
+    /// The operation stopped on its last failure, leaving the rest undone
+    stopped: bool,
+    /// The operation wasn't confirmed, so nothing was changed
+    aborted: bool,
+}
+
+impl Outcome {
+    pub fn new(root_znode_paths: &[&str]) -> Outcome {
+        Outcome {
+            roots: root_znode_paths.iter()
+                .map(|znode_path| (String::from(*znode_path), Summary::default()))
+                .collect(),
+            failures: Vec::new(),
+            stopped: false,
+            aborted: false,
+        }
+    }
+
+    /// Summary of the root `znode_path` belongs to, the deepest one if roots are nested.
+    pub fn of(&mut self, znode_path: &str) -> &mut Summary {
+        let index = self.roots.iter()
+            .enumerate()
+            .filter(|(_, (root, _))| is_within(znode_path, root))
+            .max_by_key(|(_, (root, _))| root.len())
+            .map(|(index, _)| index);
+        let index = match index {
+            Some(index) => index,
+            None => {
+                self.roots.push((String::from(znode_path), Summary::default()));
+                self.roots.len() - 1
+            }
+        };
+        &mut self.roots[index].1
+    }
+
+    /// Adds counts and failures of another part of the same operation.
+    pub fn merge(&mut self, other: Outcome) {
+        for (znode_path, summary) in other.roots {
+            let merged = self.of(&znode_path);
+            *merged = *merged + summary;
+        }
+        self.failures.extend(other.failures);
+        self.stopped |= other.stopped;
+        self.aborted |= other.aborted;
+    }
+
+    /// Counts a failed znode and records the error.
+    pub fn fail<E: Display>(&mut self, znode_path: &str, err: E) {
+        self.record_failure(znode_path, err, false);
+    }
+
+    /// Counts a failed znode, records the error and marks the operation as stopped on it.
+    pub fn stop<E: Display>(&mut self, znode_path: &str, err: E) {
+        self.fail(znode_path, err);
+        self.stopped = true;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Marks the operation as not confirmed before it changed anything.
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Counts a znode left untouched because of a version conflict.
+    pub fn conflict<E: Display>(&mut self, znode_path: &str, err: E) {
+        self.record_failure(znode_path, err, true);
+    }
+
+    fn record_failure<E: Display>(&mut self, znode_path: &str, err: E, conflict: bool) {
+        self.of(znode_path).failed += 1;
+        self.failures.push(Failure { znode_path: String::from(znode_path), error: err.to_string(), conflict });
+    }
+
+    /// Failures which fail the run.
+    pub fn errors(&self) -> Vec<&Failure> {
+        self.failures.iter()
+            .filter(|failure| !failure.conflict)
+            .collect()
+    }
+
+    pub fn conflicts(&self) -> Vec<&Failure> {
+        self.failures.iter()
+            .filter(|failure| failure.conflict)
+            .collect()
+    }
+
+    /// 1 if the run failed on any znode, 2 if it only left some untouched because of version conflicts,
+    /// 3 if it wasn't confirmed.
+    pub fn exit_code(&self) -> i32 {
+        if !self.errors().is_empty() {
+            1
+        } else if self.aborted {
+            3
+        } else if !self.conflicts().is_empty() {
+            2
+        } else {
+            0
+        }
+    }
+
+    pub fn total(&self) -> Summary {
+        self.roots.iter().fold(Summary::default(), |total, (_, summary)| total + *summary)
+    }
+}
+
+/// Minimal JSON document model, enough for the run report.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub fn render(&self) -> String {
+        match *self {
+            Json::Null => String::from("null"),
+            Json::Bool(value) => value.to_string(),
+            Json::Number(value) => value.to_string(),
+            Json::String(ref value) => json_string(value),
+            Json::Array(ref values) => {
+                let values: Vec<String> = values.iter().map(Json::render).collect();
+                format!("[{}]", values.join(","))
+            }
+            Json::Object(ref fields) => {
+                let fields: Vec<String> = fields.iter()
+                    .map(|(name, value)| format!("{}:{}", json_string(name), value.render()))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(value: &'a str) -> Json {
+        Json::String(String::from(value))
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        Json::String(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {
+        Json::Number(value as u64)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<'a> From<&'a [&'a str]> for Json {
+    fn from(values: &'a [&'a str]) -> Json {
+        Json::Array(values.iter().map(|value| Json::from(*value)).collect())
+    }
+}
+
+/// Machine-readable account of a run, written on request when the run is over.
+pub struct Report {
+    operation: &'static str,
+    parameters: Vec<(&'static str, Json)>,
+    started: SystemTime,
+}
+
+impl Report {
+    /// Starts timing a run of `operation`.
+    pub fn start(operation: &'static str, parameters: Vec<(&'static str, Json)>) -> Report {
+        Report { operation, parameters, started: SystemTime::now() }
+    }
+
+    /// Writes the report with the checksum of the archive the run has read or written.
+    /// The checksum is null when the archive can't be read, e.g. when the run stopped before writing it.
+    pub fn write(self, report_file: &str, outcome: &Outcome, archive_file: Option<&str>) -> io::Result<()> {
+        let archive = match archive_file {
+            Some(archive_file) => Json::Object(vec![
+                ("file", Json::from(archive_file)),
+                ("sha256", Json::from(sha256_file(archive_file).ok())),
+            ]),
+            None => Json::Null,
+        };
+        let document = self.into_json(outcome, archive, SystemTime::now());
+        fs::write(report_file, document.render() + "\n")
+    }
+
+    fn into_json(self, outcome: &Outcome, archive: Json, finished: SystemTime) -> Json {
+        let roots = outcome.roots.iter()
+            .map(|(znode_path, summary)| {
+                let mut fields = vec![("znode", Json::from(znode_path.as_str()))];
+                fields.extend(summary_fields(summary));
+                Json::Object(fields)
+            })
+            .collect();
+        let failures = outcome.failures.iter()
+            .map(|failure| Json::Object(vec![
+                ("znode", Json::from(failure.znode_path.as_str())),
+                ("error", Json::from(failure.error.as_str())),
+                ("conflict", Json::from(failure.conflict)),
+            ]))
+            .collect();
+        Json::Object(vec![
+            ("operation", Json::from(self.operation)),
+            ("parameters", Json::Object(self.parameters)),
+            ("started", Json::from(format_time(self.started))),
+            ("finished", Json::from(format_time(finished))),
+            ("total", Json::Object(summary_fields(&outcome.total()))),
+            ("roots", Json::Array(roots)),
+            ("failed", Json::Array(failures)),
+            ("stopped", Json::from(outcome.stopped)),
+            ("aborted", Json::from(outcome.aborted)),
+            ("archive", archive),
+        ])
+    }
+}
+
+fn summary_fields(summary: &Summary) -> Vec<(&'static str, Json)> {
+    vec![
+        ("created", Json::from(summary.created)),
+        ("updated", Json::from(summary.updated)),
+        ("dumped", Json::from(summary.dumped)),
+        ("unchanged", Json::from(summary.unchanged)),
+        ("deleted", Json::from(summary.deleted)),
+        ("skipped", Json::from(summary.skipped)),
+        ("excluded", Json::from(summary.excluded)),
+        ("missing", Json::from(summary.missing)),
+        ("failed", Json::from(summary.failed)),
+    ]
+}
+
+/// Lowercase hex SHA-256 of a file's content.
+pub fn sha256_file(file: &str) -> io::Result<String> {
+    let mut file = File::open(file)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use report::{sha256_file, Json, Outcome, Report};
+
+    #[test]
+    pub fn outcome_exit_code() {
+        let mut outcome = Outcome::new(&["/a"]);
+        assert_eq!(outcome.exit_code(), 0);
+        outcome.conflict("/a/x", "BadVersion");
+        assert_eq!(outcome.exit_code(), 2);
+        let mut aborted = Outcome::new(&["/a"]);
+        aborted.abort();
+        assert_eq!(aborted.exit_code(), 3);
+        outcome.fail("/a/y", "NoNode");
+        assert_eq!(outcome.exit_code(), 1);
+    }
+
+    #[test]
+    pub fn outcome_per_root() {
+        let mut outcome = Outcome::new(&["/a", "/a/b", "/c"]);
+        outcome.of("/a/x").created += 1;
+        outcome.of("/a/b/y").created += 1;
+        outcome.conflict("/c/z", "BadVersion");
+        outcome.fail("/a/b/y", "NoNode");
+        assert_eq!(outcome.of("/a").created, 1);
+        assert_eq!(outcome.of("/a/b").created, 1);
+        assert_eq!(outcome.of("/c").failed, 1);
+        assert_eq!(outcome.of("/a/b").failed, 1);
+        assert_eq!(outcome.failures.len(), 2);
+        assert_eq!(outcome.errors().iter().map(|failure| failure.znode_path.as_str()).collect::<Vec<_>>(), ["/a/b/y"]);
+        let total = outcome.total();
+        assert_eq!((total.created, total.failed), (2, 2));
+
+        let mut read = Outcome::new(&["/a", "/a/b", "/c"]);
+        read.of("/a/b/z").skipped += 1;
+        outcome.merge(read);
+        assert_eq!(outcome.of("/a/b").skipped, 1);
+        assert_eq!(outcome.total().skipped, 1);
+        assert!(!outcome.is_stopped());
+
+        let mut stopped = Outcome::new(&["/a"]);
+        stopped.stop("/a/x", "SessionExpired");
+        outcome.merge(stopped);
+        assert!(outcome.is_stopped());
+        assert_eq!(outcome.errors().len(), 2);
+
+        let mut outcome = Outcome::new(&["/a", "/ab"]);
+        outcome.of("/ab/x").created += 1;
+        outcome.of("/a/x").updated += 1;
+        assert_eq!((outcome.of("/a").created, outcome.of("/a").updated), (0, 1));
+        assert_eq!((outcome.of("/ab").created, outcome.of("/ab").updated), (1, 0));
+    }
+
+    #[test]
+    pub fn report_json() {
+        let mut outcome = Outcome::new(&["/a"]);
+        outcome.of("/a").dumped = 2;
+        outcome.fail("/a/b", "NoNode");
+        let mut report = Report::start("dump", vec![("znodes", Json::from(&["/a"][..])), ("file", Json::from(Some("dump.tar.gz")))]);
+        report.started = UNIX_EPOCH;
+        let json = report.into_json(&outcome, Json::Null, UNIX_EPOCH + Duration::from_secs(1)).render();
+        let counts = "\"created\":0,\"updated\":0,\"dumped\":2,\"unchanged\":0,\"deleted\":0,\"skipped\":0,\"excluded\":0,\"missing\":0,\"failed\":1";
+        assert_eq!(json, format!("{{\"operation\":\"dump\",\"parameters\":{{\"znodes\":[\"/a\"],\"file\":\"dump.tar.gz\"}},\
+                                  \"started\":\"1970-01-01T00:00:00.000Z\",\"finished\":\"1970-01-01T00:00:01.000Z\",\
+                                  \"total\":{{{0}}},\"roots\":[{{\"znode\":\"/a\",{0}}}],\
+                                  \"failed\":[{{\"znode\":\"/a/b\",\"error\":\"NoNode\",\"conflict\":false}}],\"stopped\":false,\"aborted\":false,\"archive\":null}}", counts));
+    }
+
+    #[test]
+    pub fn report_unreadable_archive() {
+        let report_file = "report_unreadable_archive.json";
+        Report::start("delete", vec![]).write(report_file, &Outcome::new(&["/a"]), Some("report_missing_archive.tar.gz")).unwrap();
+        let json = fs::read_to_string(report_file).unwrap();
+        fs::remove_file(report_file).unwrap();
+        assert!(json.ends_with("\"archive\":{\"file\":\"report_missing_archive.tar.gz\",\"sha256\":null}}\n"));
+    }
+
+    #[test]
+    pub fn sha256_file_test() {
+        let file = "sha256_file_test.txt";
+        fs::write(file, "abc").unwrap();
+        let checksum = sha256_file(file);
+        fs::remove_file(file).unwrap();
+        assert_eq!(checksum.unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use logging::civil_from_days;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+/// Appended to the file name of a template without placeholders, in front of its extension.
+const TIMESTAMP_PLACEHOLDERS: &str = "-%Y%m%dT%H%M%SZ";
+
+/// Which archives of a schedule survive pruning, all of them if neither rule is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    /// How many of the newest archives to keep
+    pub keep_last: Option<usize>,
+    /// For how many days back from today to keep the newest archive of the day
+    pub keep_daily: Option<usize>,
+}
+
+/// Runs `dump` every `interval` with a file named after `template` by the UTC start time, then prunes
+/// the archives of the template in its directory which `retention` doesn't keep. Files not matching
+/// the template are never touched. A failed dump doesn't stop the schedule, but skips pruning, so `dump`
+/// is expected to connect on its own every time rather than share a session which may have expired.
+pub fn run_every<F>(template: &str, interval: Duration, retention: Retention, mut dump: F) -> ! where F: FnMut(&str) -> bool {
+    let template = with_timestamp(template);
+    loop {
+        let started = Instant::now();
+        let file = format_file_name(&template, SystemTime::now());
+        info!(event = "scheduled", file = file.as_str(); "Scheduled dump started");
+        if panic::catch_unwind(AssertUnwindSafe(|| dump(&file))).unwrap_or(false) {
+            prune(&template, retention);
+        } else {
+            warn!(file = file.as_str(); "Scheduled dump failed, nothing is pruned");
+        }
+        thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+}
+
+/// Checks that placeholders are only in the file name of the template, as its archives are pruned in one directory.
+pub fn validate_template(template: &str) -> Result<(), String> {
+    let file_name_start = template.rfind('/').map_or(0, |slash| slash + 1);
+    if template[..file_name_start].contains('%') {
+        return Err(format!("Placeholders of --every are only replaced in the file name, not in the directory of '{}'", template));
+    }
+    Ok(())
+}
+
+/// The template itself if it has placeholders, otherwise the template with the start time put in front
+/// of the extension, e.g. `zk-dump.tar.gz` becomes `zk-dump-%Y%m%dT%H%M%SZ.tar.gz`.
+fn with_timestamp(template: &str) -> String {
+    let file_name_start = template.rfind('/').map_or(0, |slash| slash + 1);
+    if template[file_name_start..].contains('%') {
+        return String::from(template);
+    }
+    let extension_start = template[file_name_start..].find('.').map_or(template.len(), |dot| file_name_start + dot);
+    format!("{}{}{}", &template[..extension_start], TIMESTAMP_PLACEHOLDERS, &template[extension_start..])
+}
+
+/// Replaces `%Y`, `%m`, `%d`, `%H`, `%M`, `%S` with the UTC time and `%%` with `%`, anything else is kept as is.
+fn format_file_name(template: &str, time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let seconds_of_day = seconds % SECONDS_PER_DAY;
+    let mut file_name = String::with_capacity(template.len() + 16);
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            file_name.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => file_name.push_str(&format!("{:04}", year)),
+            Some('m') => file_name.push_str(&format!("{:02}", month)),
+            Some('d') => file_name.push_str(&format!("{:02}", day)),
+            Some('H') => file_name.push_str(&format!("{:02}", seconds_of_day / 3600)),
+            Some('M') => file_name.push_str(&format!("{:02}", seconds_of_day / 60 % 60)),
+            Some('S') => file_name.push_str(&format!("{:02}", seconds_of_day % 60)),
+            Some('%') => file_name.push('%'),
+            Some(other) => {
+                file_name.push('%');
+                file_name.push(other);
+            }
+            None => file_name.push('%'),
+        }
+    }
+    file_name
+}
+
+/// Seconds since the epoch a file name was formatted from `template` with, if it was.
+fn parse_file_name(template: &str, file_name: &str) -> Option<u64> {
+    let (mut year, mut month, mut day, mut hour, mut minute, mut second) = (1970, 1, 1, 0, 0, 0);
+    let mut rest = file_name;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        let field = match (c, chars.clone().next()) {
+            ('%', Some('Y')) => Some((&mut year, 4)),
+            ('%', Some('m')) => Some((&mut month, 2)),
+            ('%', Some('d')) => Some((&mut day, 2)),
+            ('%', Some('H')) => Some((&mut hour, 2)),
+            ('%', Some('M')) => Some((&mut minute, 2)),
+            ('%', Some('S')) => Some((&mut second, 2)),
+            _ => None,
+        };
+        match field {
+            Some((value, digits)) => {
+                chars.next();
+                let number = rest.get(..digits).filter(|number| number.bytes().all(|byte| byte.is_ascii_digit()))?;
+                *value = number.parse().ok()?;
+                rest = &rest[digits..];
+            }
+            None => {
+                if c == '%' && chars.clone().next() == Some('%') {
+                    chars.next();
+                }
+                rest = rest.strip_prefix(c)?;
+            }
+        }
+    }
+    if !rest.is_empty() || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day);
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second)
+}
+
+/// Days from 1970-01-01 to a Gregorian date, the inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn prune(template: &str, retention: Retention) {
+    let path = Path::new(template);
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name_template = path.file_name().and_then(|name| name.to_str()).unwrap_or(template);
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(directory = directory.to_string_lossy().as_ref(), reason:% = err; "Can't list archives to prune");
+            return;
+        }
+    };
+    let archives = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|file_name| parse_file_name(file_name_template, &file_name).map(|time| (file_name, time)))
+        .collect();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    for file_name in archives_to_prune(archives, retention, now) {
+        let file = directory.join(&file_name);
+        match fs::remove_file(&file) {
+            Ok(_) => info!(event = "pruned", file = file.to_string_lossy().as_ref(); "Archive pruned"),
+            Err(err) => warn!(file = file.to_string_lossy().as_ref(), reason:% = err; "Can't prune archive"),
+        }
+    }
+}
+
+/// File names of the archives `retention` doesn't keep, given archives with their times in seconds since the epoch.
+/// The newest archive is always kept.
+fn archives_to_prune(mut archives: Vec<(String, u64)>, retention: Retention, now: u64) -> Vec<String> {
+    if retention.keep_last.is_none() && retention.keep_daily.is_none() {
+        return Vec::new();
+    }
+    archives.sort_by(|left, right| right.1.cmp(&left.1).then_with(|| right.0.cmp(&left.0)));
+    let today = now / SECONDS_PER_DAY;
+    let mut kept_days = HashSet::new();
+    archives.into_iter()
+        .enumerate()
+        .filter_map(|(index, (file_name, time))| {
+            let day = time / SECONDS_PER_DAY;
+            let is_last = index == 0 || retention.keep_last.is_some_and(|keep_last| index < keep_last);
+            let is_daily = retention.keep_daily.is_some_and(|keep_daily| today.saturating_sub(day) < keep_daily as u64) && kept_days.insert(day);
+            if is_last || is_daily { None } else { Some(file_name) }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use schedule::{archives_to_prune, format_file_name, parse_file_name, validate_template, with_timestamp, Retention};
+
+    #[test]
+    pub fn with_timestamp_test() {
+        assert_eq!(with_timestamp("zk-dump.tar.gz"), "zk-dump-%Y%m%dT%H%M%SZ.tar.gz");
+        assert_eq!(with_timestamp("/backups.d/zk-dump"), "/backups.d/zk-dump-%Y%m%dT%H%M%SZ");
+        assert_eq!(with_timestamp("/backups/zk-%Y-%H.tar.gz"), "/backups/zk-%Y-%H.tar.gz");
+    }
+
+    #[test]
+    pub fn validate_template_test() {
+        assert!(validate_template("zk-dump-%Y.tar.gz").is_ok());
+        assert!(validate_template("/backups.d/zk-dump.tar.gz").is_ok());
+        assert!(validate_template("/backups/%Y/zk-dump.tar.gz").is_err());
+    }
+
+    #[test]
+    pub fn file_name_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_614_834_367);
+        let file_name = format_file_name("zk-dump-%Y%m%dT%H%M%SZ.tar.gz", time);
+        assert_eq!(file_name, "zk-dump-20210304T050607Z.tar.gz");
+        assert_eq!(parse_file_name("zk-dump-%Y%m%dT%H%M%SZ.tar.gz", &file_name), Some(1_614_834_367));
+        assert_eq!(format_file_name("100%%-%Y-%q", UNIX_EPOCH), "100%-1970-%q");
+        assert_eq!(parse_file_name("100%%-%Y-%q", "100%-1970-%q"), Some(0));
+        assert_eq!(parse_file_name("zk-dump-%Y%m%d.tar.gz", "zk-dump-20210304.tar.gz.partial"), None);
+        assert_eq!(parse_file_name("zk-dump-%Y%m%d.tar.gz", "zk-dump-20211304.tar.gz"), None);
+        assert_eq!(parse_file_name("zk-dump-%Y%m%d.tar.gz", "zk-dump.tar.gz"), None);
+    }
+
+    #[test]
+    pub fn prune_by_retention() {
+        let hour = 3600;
+        let now = 10 * 24 * hour;
+        let archives: Vec<(String, u64)> = [1, 2, 25, 26, 49, 24 * 5].iter()
+            .map(|hours_ago| (format!("{}h", hours_ago), now - hours_ago * hour))
+            .collect();
+        let mut pruned = archives_to_prune(archives.clone(), Retention { keep_last: Some(2), keep_daily: None }, now);
+        pruned.sort();
+        assert_eq!(pruned, ["120h", "25h", "26h", "49h"]);
+        let mut pruned = archives_to_prune(archives.clone(), Retention { keep_last: Some(1), keep_daily: Some(3) }, now);
+        pruned.sort();
+        assert_eq!(pruned, ["120h", "26h", "2h", "49h"]);
+        assert_eq!(archives_to_prune(archives.clone(), Retention { keep_last: Some(0), keep_daily: None }, now).len(), archives.len() - 1);
+        assert!(archives_to_prune(archives, Retention::default(), now).is_empty());
+    }
+}
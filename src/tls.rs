@@ -0,0 +1,252 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+/// Servers never negotiate a longer session timeout than this by default, even if a shorter one is asked for
+const MIN_IDLE_TIMEOUT: Duration = Duration::from_secs(40);
+
+/// Files needed to connect to the secure client port of zookeeper.
+#[derive(Clone, Debug, Default)]
+pub struct TlsSettings {
+    /// PEM bundle of CA certificates trusted in addition to the system ones
+    pub ca_file: Option<String>,
+    /// PEM client certificate
+    pub cert_file: Option<String>,
+    /// PEM PKCS#8 key of the client certificate
+    pub key_file: Option<String>,
+}
+
+/// Local endpoints forwarding connections to the servers over TLS, they stop accepting connections once dropped.
+pub struct Tunnels {
+    local_addresses: Vec<SocketAddr>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Tunnels {
+    /// The servers string pointing at the local endpoints.
+    pub fn servers(&self) -> String {
+        self.local_addresses.iter()
+            .map(SocketAddr::to_string)
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+impl Drop for Tunnels {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        for local_address in &self.local_addresses {
+            // Wakes up the accepting thread, so that it sees it is stopped
+            let _ = TcpStream::connect(local_address);
+        }
+    }
+}
+
+/// The zookeeper client only talks plain TCP, so every server gets a local endpoint forwarding
+/// its connections over TLS. The client pings well within the session timeout, so a connection
+/// quiet for longer is stuck and gets closed.
+pub fn start_tunnels(servers: &str, settings: &TlsSettings, session_timeout: Duration) -> Result<Tunnels> {
+    let connector = connector(settings)?;
+    let idle_timeout = session_timeout.max(MIN_IDLE_TIMEOUT);
+    let stopped = Arc::new(AtomicBool::new(false));
+    let mut local_addresses = Vec::new();
+    for server in servers.split(',') {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        local_addresses.push(listener.local_addr()?);
+        let server = String::from(server);
+        let connector = connector.clone();
+        let stopped = stopped.clone();
+        thread::spawn(move || {
+            for local in listener.incoming().flatten() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let server = server.clone();
+                let connector = connector.clone();
+                thread::spawn(move || {
+                    if let Err(err) = forward(local, &server, &connector, idle_timeout) {
+                        warn!(server = server.as_str(), reason:% = err; "TLS connection failed");
+                    }
+                });
+            }
+        });
+    }
+    Ok(Tunnels { local_addresses, stopped })
+}
+
+fn connector(settings: &TlsSettings) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ref ca_file) = settings.ca_file {
+        builder.add_root_certificate(Certificate::from_pem(&fs::read(ca_file)?).map_err(tls_error)?);
+    }
+    match (&settings.cert_file, &settings.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            builder.identity(Identity::from_pkcs8(&fs::read(cert_file)?, &fs::read(key_file)?).map_err(tls_error)?);
+        }
+        (None, None) => {}
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "Client certificate and key must be given together")),
+    }
+    builder.build().map_err(tls_error)
+}
+
+/// Pumps data between the local connection and a TLS connection to the server until either side closes,
+/// a thread for each direction. Closing one side shuts down the other one, which ends both threads.
+fn forward(local: TcpStream, server: &str, connector: &TlsConnector, idle_timeout: Duration) -> Result<()> {
+    let remote = connect(server, server_host(server), connector)?;
+    let remote_socket = remote.get_ref().try_clone()?;
+    for socket in &[&local, &remote_socket] {
+        socket.set_read_timeout(Some(idle_timeout))?;
+        socket.set_write_timeout(Some(idle_timeout))?;
+    }
+    let remote = Arc::new(Mutex::new(remote));
+    let upstream = {
+        let local = local.try_clone()?;
+        let remote_socket = remote_socket.try_clone()?;
+        let remote = remote.clone();
+        thread::spawn(move || {
+            let result = copy_to_remote(&local, &remote);
+            let _ = remote_socket.shutdown(Shutdown::Both);
+            result
+        })
+    };
+    let result = copy_to_local(&remote_socket, &remote, &local);
+    let _ = local.shutdown(Shutdown::Both);
+    let _ = remote_socket.shutdown(Shutdown::Both);
+    let upstream_result = upstream.join().unwrap_or(Ok(()));
+    result.and(upstream_result)
+}
+
+fn copy_to_remote(mut local: &TcpStream, remote: &Mutex<TlsStream<TcpStream>>) -> Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        match local.read(&mut buffer)? {
+            0 => return Ok(()),
+            read => remote.lock().unwrap().write_all(&buffer[..read])?,
+        }
+    }
+}
+
+/// Waits for data on the socket without holding the TLS stream, so that the other direction can write meanwhile.
+/// The stream only reads what has arrived, as a TLS record without data, e.g. a session ticket, would block it.
+fn copy_to_local(remote_socket: &TcpStream, remote: &Mutex<TlsStream<TcpStream>>, mut local: &TcpStream) -> Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        if remote.lock().unwrap().buffered_read_size().map_err(tls_error)? == 0 && remote_socket.peek(&mut buffer[..1])? == 0 {
+            return Ok(());
+        }
+        let read = {
+            let mut remote = remote.lock().unwrap();
+            remote_socket.set_nonblocking(true)?;
+            let read = remote.read(&mut buffer);
+            remote_socket.set_nonblocking(false)?;
+            read
+        };
+        match read {
+            Ok(0) => return Ok(()),
+            Ok(read) => local.write_all(&buffer[..read])?,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Host name of a `host:port` server the certificate is checked against, without the brackets of an IPv6 address.
+fn server_host(server: &str) -> &str {
+    let host = server.rsplitn(2, ':').last().unwrap_or(server);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn connect(server: &str, host: &str, connector: &TlsConnector) -> Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(server)?;
+    connector.connect(host, stream).map_err(tls_error)
+}
+
+fn tls_error<E: ToString>(err: E) -> Error {
+    Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use native_tls::{Identity, TlsAcceptor};
+
+    use tls::{server_host, start_tunnels, TlsSettings};
+
+    /// Local TLS server requiring no client certificate, echoing back everything it receives.
+    fn echo_server() -> String {
+        let identity = Identity::from_pkcs8(&fs::read("test-tls/server.pem").unwrap(), &fs::read("test-tls/server.key").unwrap()).unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let stream = listener.incoming().next().unwrap().unwrap();
+            let mut stream = acceptor.accept(stream).unwrap();
+            let mut buffer = [0; 64];
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => stream.write_all(&buffer[..read]).unwrap(),
+                }
+            }
+        });
+        format!("localhost:{}", port)
+    }
+
+    fn client_settings() -> TlsSettings {
+        TlsSettings {
+            ca_file: Some(String::from("test-tls/ca.pem")),
+            cert_file: Some(String::from("test-tls/client.pem")),
+            key_file: Some(String::from("test-tls/client.key")),
+        }
+    }
+
+    #[test]
+    pub fn tunnel_round_trip() {
+        let tunnels = start_tunnels(&echo_server(), &client_settings(), Duration::from_secs(15)).unwrap();
+        let mut stream = TcpStream::connect(tunnels.servers().as_str()).unwrap();
+        for message in &[&b"ruok"[..], &b"stat"[..]] {
+            stream.write_all(message).unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer[..], *message);
+        }
+    }
+
+    #[test]
+    pub fn tunnel_closed_when_dropped() {
+        let tunnels = start_tunnels(&echo_server(), &client_settings(), Duration::from_secs(15)).unwrap();
+        let local_servers = tunnels.servers();
+        drop(tunnels);
+        let closed = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            TcpStream::connect(local_servers.as_str()).is_err()
+        });
+        assert!(closed);
+    }
+
+    #[test]
+    pub fn server_host_test() {
+        assert_eq!(server_host("zk1.example.com:2281"), "zk1.example.com");
+        assert_eq!(server_host("10.0.0.1:2281"), "10.0.0.1");
+        assert_eq!(server_host("[::1]:2281"), "::1");
+        assert_eq!(server_host("[fe80::1]:2281"), "fe80::1");
+    }
+
+    #[test]
+    pub fn certificate_without_key() {
+        let settings = TlsSettings { cert_file: Some(String::from("test-tls/client.pem")), ..Default::default() };
+        assert!(start_tunnels("localhost:2281", &settings, Duration::from_secs(15)).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use zookeeper::{Stat, ZkError, ZkResult};
+
+use archive::{compare_children, Owner};
+use connection::ZkClient;
+use error::Result;
+use report::Outcome;
+
+/// How many znodes ahead of the one being visited each worker may fetch.
+const PREFETCH_PER_WORKER: usize = 16;
+
+/// A znode read by a traversal worker.
+pub struct FetchedZnode {
+    pub data: Vec<u8>,
+    pub stat: Stat,
+    /// Full paths of the children which aren't excluded, in archive order
+    pub children: Vec<String>,
+    /// How many children are excluded
+    pub excluded: usize,
+    /// The data is left out as it is the same as in the base archive of an incremental dump
+    pub unchanged: bool,
+}
+
+/// Zxids of the last change of the data and of the children of a znode.
+pub type Zxids = (i64, i64);
+
+/// What an incremental dump knows about the znodes of its base archive.
+#[derive(Debug, Default)]
+pub struct Base {
+    pub zxids: HashMap<String, Zxids>,
+    /// Full paths of the children of every znode in the base archive
+    pub children: HashMap<String, HashSet<String>>,
+}
+
+type Fetched = ZkResult<Option<FetchedZnode>>;
+
+/// Visits the trees under `tree_root_znode_paths` in pre-order, children ordered by `compare_children`.
+/// Znodes are fetched by `parallelism` workers, each with at most one request in flight, up to a bounded
+/// number of znodes ahead of the visited one, so the visiting order is the same as of a serial traversal.
+/// Returns how many znodes of every root were visited, skipped as ephemeral and how many subtrees were excluded.
+/// If `keep_going`, znodes which can't be read are recorded as failed along with their subtrees, unless the session is lost,
+/// otherwise the traversal stops on the first of them, which is recorded in the outcome.
+/// Znodes deleted before they are read are counted as missing along with their subtrees whatever `keep_going` is.
+pub fn traverse<F>(zk_client: &ZkClient, tree_root_znode_paths: &[&str], excluded_znodes: &[&str], include_ephemeral: bool,
+                   parallelism: usize, keep_going: bool, visit: F) -> Result<Outcome> where F: FnMut(&str, FetchedZnode) -> Result<()> {
+    let fetch = |znode_path: &str| fetch_znode(zk_client, znode_path, excluded_znodes, include_ephemeral);
+    traverse_with(tree_root_znode_paths, excluded_znodes, parallelism, keep_going, fetch, visit)
+}
+
+pub fn traverse_with<G, F>(tree_root_znode_paths: &[&str], excluded_znodes: &[&str], parallelism: usize, keep_going: bool, fetch: G,
+                       mut visit: F) -> Result<Outcome>
+    where G: Fn(&str) -> Fetched + Sync, F: FnMut(&str, FetchedZnode) -> Result<()> {
+    let parallelism = parallelism.max(1);
+    let (task_sender, task_receiver) = mpsc::channel::<String>();
+    let task_receiver = Mutex::new(task_receiver);
+    let (fetched_sender, fetched_receiver) = mpsc::channel::<(String, Fetched)>();
+    thread::scope(|scope| {
+        for _ in 0..parallelism {
+            let task_receiver = &task_receiver;
+            let fetch = &fetch;
+            let fetched_sender = fetched_sender.clone();
+            scope.spawn(move || loop {
+                let task = task_receiver.lock().unwrap().recv();
+                let znode_path = match task {
+                    Ok(znode_path) => znode_path,
+                    Err(_) => break,
+                };
+                let fetched = fetch(&znode_path);
+                if fetched_sender.send((znode_path, fetched)).is_err() {
+                    break;
+                }
+            });
+        }
+        let (excluded, stack): (Vec<String>, Vec<String>) = tree_root_znode_paths.iter()
+            .rev()
+            .map(|znode_path| String::from(*znode_path))
+            .partition(|znode_path| excluded_znodes.contains(&znode_path.as_str()));
+        log_excluded(&excluded);
+        let mut outcome = Outcome::new(tree_root_znode_paths);
+        for znode_path in &excluded {
+            outcome.of(znode_path).excluded += 1;
+        }
+        let mut traversal = Traversal {
+            outcome,
+            keep_going,
+            stack,
+            submitted: HashSet::new(),
+            fetched: HashMap::new(),
+            task_sender,
+            fetched_receiver,
+            prefetch: parallelism * PREFETCH_PER_WORKER,
+        };
+        // Dropping the traversal closes the task channel, which stops the workers
+        traversal.visit_all(&mut visit)?;
+        Ok(traversal.outcome)
+    })
+}
+
+/// Pre-order traversal state of the visiting thread.
+struct Traversal {
+    /// Znodes to visit, the next one on top
+    stack: Vec<String>,
+    /// Znodes sent to workers and not visited yet
+    submitted: HashSet<String>,
+    fetched: HashMap<String, Fetched>,
+    task_sender: Sender<String>,
+    fetched_receiver: Receiver<(String, Fetched)>,
+    prefetch: usize,
+    /// Visited znodes are counted as dumped
+    outcome: Outcome,
+    keep_going: bool,
+}
+
+impl Traversal {
+    fn visit_all<F>(&mut self, visit: &mut F) -> Result<()> where F: FnMut(&str, FetchedZnode) -> Result<()> {
+        while let Some(znode_path) = self.stack.last().cloned() {
+            self.submit_upcoming();
+            let fetched = self.wait_for(&znode_path);
+            self.stack.pop();
+            self.submitted.remove(&znode_path);
+            match fetched {
+                Ok(Some(znode)) => {
+                    self.outcome.of(&znode_path).excluded += znode.excluded;
+                    self.stack.extend(znode.children.iter().rev().cloned());
+                    let unchanged = znode.unchanged;
+                    visit(&znode_path, znode)?;
+                    if unchanged {
+                        self.outcome.of(&znode_path).unchanged += 1;
+                    } else {
+                        self.outcome.of(&znode_path).dumped += 1;
+                    }
+                }
+                Ok(None) => self.outcome.of(&znode_path).skipped += 1,
+                Err(ZkError::NoNode) => {
+                    // Deleted since its parent was listed, the fence of the dump settles what the archive holds
+                    info!(event = "vanished", znode = znode_path.as_str(); "Znode deleted while traversing");
+                    self.outcome.of(&znode_path).missing += 1;
+                }
+                Err(err) => {
+                    error!(event = "failed", znode = znode_path.as_str(), reason:% = err; "Can't read znode");
+                    if self.keep_going && err != ZkError::SessionExpired {
+                        self.outcome.fail(&znode_path, err);
+                    } else {
+                        self.outcome.stop(&znode_path, err);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the next znodes in pre-order, as far as it is known yet, to the workers.
+    fn submit_upcoming(&mut self) {
+        let mut upcoming = Vec::new();
+        let mut stack_index = self.stack.len();
+        let mut expanded: Vec<&str> = Vec::new();
+        for _ in 0..self.prefetch {
+            let znode_path = match expanded.pop() {
+                Some(znode_path) => znode_path,
+                None if stack_index > 0 => {
+                    stack_index -= 1;
+                    self.stack[stack_index].as_str()
+                }
+                None => break,
+            };
+            if let Some(Ok(Some(znode))) = self.fetched.get(znode_path) {
+                expanded.extend(znode.children.iter().rev().map(String::as_str));
+            }
+            if !self.submitted.contains(znode_path) {
+                upcoming.push(String::from(znode_path));
+            }
+        }
+        for znode_path in upcoming {
+            self.submitted.insert(znode_path.clone());
+            self.task_sender.send(znode_path).expect("Traversal workers stopped");
+        }
+    }
+
+    fn wait_for(&mut self, znode_path: &str) -> Fetched {
+        loop {
+            if let Some(fetched) = self.fetched.remove(znode_path) {
+                return fetched;
+            }
+            let (fetched_path, fetched) = self.fetched_receiver.recv().expect("Traversal workers stopped");
+            self.fetched.insert(fetched_path, fetched);
+        }
+    }
+}
+
+/// Reads a znode with its children which aren't excluded, ephemeral ones are left out unless `include_ephemeral`.
+pub fn fetch_znode(zk_client: &ZkClient, znode_path: &str, excluded_znodes: &[&str], include_ephemeral: bool) -> Fetched {
+    let (data, stat) = zk_client.get_data(znode_path, false)?;
+    debug!(event = "visited", znode = znode_path; "Znode visited");
+    let is_ephemeral = Owner::from_ephemeral_owner(stat.ephemeral_owner).is_ephemeral();
+    if is_ephemeral && !include_ephemeral {
+        info!(event = "skipped", znode = znode_path, reason = "ephemeral"; "Znode skipped");
+        return Ok(None);
+    }
+    if is_ephemeral {
+        // Ephemeral znodes can't have children
+        return Ok(Some(FetchedZnode { data, stat, children: Vec::new(), excluded: 0, unchanged: false }));
+    }
+    let (children, excluded) = sorted_children(list_children(zk_client, znode_path)?, excluded_znodes);
+    Ok(Some(FetchedZnode { data, stat, children, excluded, unchanged: false }))
+}
+
+/// Like `fetch_znode`, but reads the data only if it changed since the base archive
+/// and lists the children only if they changed.
+pub fn fetch_changed_znode(zk_client: &ZkClient, znode_path: &str, excluded_znodes: &[&str], include_ephemeral: bool, base: &Base) -> Fetched {
+    let (mzxid, pzxid) = match base.zxids.get(znode_path) {
+        Some(zxids) => *zxids,
+        None => return fetch_znode(zk_client, znode_path, excluded_znodes, include_ephemeral),
+    };
+    let stat = zk_client.exists(znode_path, false)?.ok_or(ZkError::NoNode)?;
+    if stat.mzxid != mzxid || Owner::from_ephemeral_owner(stat.ephemeral_owner).is_ephemeral() {
+        return fetch_znode(zk_client, znode_path, excluded_znodes, include_ephemeral);
+    }
+    debug!(event = "visited", znode = znode_path, reason = "unchanged"; "Znode visited");
+    let children = if stat.pzxid == pzxid {
+        base.children.get(znode_path).map(|children| children.iter().cloned().collect()).unwrap_or_default()
+    } else {
+        list_children(zk_client, znode_path)?
+    };
+    let (children, excluded) = sorted_children(children, excluded_znodes);
+    Ok(Some(FetchedZnode { data: Vec::new(), stat, children, excluded, unchanged: true }))
+}
+
+/// Full paths of the children of a znode.
+fn list_children(zk_client: &ZkClient, znode_path: &str) -> ZkResult<Vec<String>> {
+    let parent_path = if znode_path.ends_with('/') { String::from(znode_path) } else { String::from(znode_path) + "/" };
+    Ok(zk_client.get_children(znode_path, false)?.into_iter()
+        .map(|child| parent_path.clone() + &child)
+        .collect())
+}
+
+/// Children paths in archive order without the excluded ones, along with how many were excluded.
+fn sorted_children(mut children: Vec<String>, excluded_znodes: &[&str]) -> (Vec<String>, usize) {
+    children.sort_by(|left, right| compare_children(child_name(left), child_name(right)));
+    let (excluded, children): (Vec<String>, Vec<String>) = children.into_iter()
+        .partition(|child_path| excluded_znodes.contains(&child_path.as_str()));
+    log_excluded(&excluded);
+    (children, excluded.len())
+}
+
+fn child_name(child_path: &str) -> &str {
+    child_path.rsplit('/').next().unwrap_or("")
+}
+
+fn log_excluded(excluded_paths: &[String]) {
+    for znode_path in excluded_paths {
+        info!(event = "excluded", znode = znode_path.as_str(); "Znode excluded");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use zookeeper::{Stat, ZkError};
+
+    use traversal::{traverse_with, FetchedZnode};
+
+    fn stat() -> Stat {
+        Stat {
+            czxid: 0, mzxid: 0, ctime: 0, mtime: 0, version: 0, cversion: 0, aversion: 0, ephemeral_owner: 0, data_length: 0, num_children: 0, pzxid: 0,
+        }
+    }
+
+    /// Tree `/a/<i>/<j>` with `i`, `j` in `0..width`, children listed in archive order.
+    fn fetch(znode_path: &str, width: usize) -> FetchedZnode {
+        let depth = znode_path.matches('/').count();
+        let children = if depth < 3 {
+            (0..width).map(|i| format!("{}/{}", znode_path, i)).collect()
+        } else {
+            Vec::new()
+        };
+        FetchedZnode { data: znode_path.as_bytes().to_vec(), stat: stat(), children, excluded: 0, unchanged: false }
+    }
+
+    #[test]
+    pub fn traverse_in_pre_order() {
+        let width = 7;
+        let mut expected = vec![String::from("/a")];
+        for i in 0..width {
+            expected.push(format!("/a/{}", i));
+            for j in 0..width {
+                expected.push(format!("/a/{}/{}", i, j));
+            }
+        }
+        for parallelism in &[1, 4] {
+            let mut visited = Vec::new();
+            traverse_with(&["/a"], &[], *parallelism, false, |znode_path| Ok(Some(fetch(znode_path, width))), |znode_path, znode| {
+                assert_eq!(znode.data, znode_path.as_bytes());
+                visited.push(String::from(znode_path));
+                Ok(())
+            }).unwrap();
+            assert_eq!(visited, expected);
+        }
+    }
+
+    #[test]
+    pub fn skip_excluded_and_absent() {
+        let mut visited = Vec::new();
+        let outcome = traverse_with(&["/a", "/b"], &["/b"], 2, false, |znode_path| {
+            if znode_path == "/a/1" { Ok(None) } else { Ok(Some(fetch(znode_path, 2))) }
+        }, |znode_path, _| {
+            visited.push(String::from(znode_path));
+            Ok(())
+        }).unwrap();
+        assert_eq!(visited, ["/a", "/a/0", "/a/0/0", "/a/0/1"]);
+        let summary = outcome.total();
+        assert_eq!((summary.dumped, summary.skipped, summary.excluded), (4, 1, 1));
+    }
+
+    #[test]
+    pub fn count_unchanged() {
+        let outcome = traverse_with(&["/a"], &[], 2, false, |znode_path| {
+            Ok(Some(FetchedZnode { unchanged: znode_path != "/a/1", ..fetch(znode_path, 2) }))
+        }, |_, _| Ok(())).unwrap();
+        let summary = outcome.total();
+        assert_eq!((summary.dumped, summary.unchanged), (1, 6));
+    }
+
+    #[test]
+    pub fn skip_vanished() {
+        let mut visited = Vec::new();
+        let outcome = traverse_with(&["/a"], &[], 2, false, |znode_path| {
+            if znode_path == "/a/0" { Err(ZkError::NoNode) } else { Ok(Some(fetch(znode_path, 2))) }
+        }, |znode_path, _| {
+            visited.push(String::from(znode_path));
+            Ok(())
+        }).unwrap();
+        assert_eq!(visited, ["/a", "/a/1", "/a/1/0", "/a/1/1"]);
+        let summary = outcome.total();
+        assert_eq!((summary.dumped, summary.missing, summary.failed), (4, 1, 0));
+    }
+
+    #[test]
+    pub fn stop_on_error() {
+        let fetches = AtomicUsize::new(0);
+        let outcome = traverse_with(&["/a"], &[], 4, false, |znode_path| {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            if znode_path == "/a/2" { Err(ZkError::NoAuth) } else { Ok(Some(fetch(znode_path, 50))) }
+        }, |_, _| Ok(())).unwrap();
+        assert!(outcome.is_stopped());
+        assert_eq!(outcome.errors().iter().map(|failure| failure.znode_path.as_str()).collect::<Vec<_>>(), ["/a/2"]);
+        assert!(fetches.load(Ordering::SeqCst) < 50 * 50);
+    }
+
+    #[test]
+    pub fn keep_going_past_unreadable() {
+        let mut visited = Vec::new();
+        let outcome = traverse_with(&["/a"], &[], 2, true, |znode_path| {
+            if znode_path == "/a/0" { Err(ZkError::NoAuth) } else { Ok(Some(fetch(znode_path, 2))) }
+        }, |znode_path, _| {
+            visited.push(String::from(znode_path));
+            Ok(())
+        }).unwrap();
+        assert_eq!(visited, ["/a", "/a/1", "/a/1/0", "/a/1/1"]);
+        assert_eq!(outcome.errors().len(), 1);
+        assert_eq!(outcome.errors()[0].znode_path, "/a/0");
+
+        let outcome = traverse_with(&["/a"], &[], 2, true, |_| Err(ZkError::SessionExpired), |_, _| Ok(()));
+        assert!(outcome.unwrap().is_stopped());
+    }
+}
\ No newline at end of file
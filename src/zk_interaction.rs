@@ -1,133 +1,1726 @@
-extern crate zookeeper;
-
 use std::borrow::BorrowMut;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::time::Duration;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use flate2::Compression;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use tar::{Archive, Builder, Header};
 
-use self::zookeeper::{Acl, CreateMode, ZooKeeper};
+use zookeeper::{Acl, CreateMode, Stat, WatchedEvent, WatchedEventType, ZkError, ZkResult};
+
+use archive::{format_session, format_zxid, Manifest, Owner, ZnodeMeta, ZnodeMode, DATA_FILE_NAME, MANIFEST_FILE_NAME, META_FILE_NAME, SEQUENCE_LENGTH};
+use change_log::{read_change_log, Change, ChangeLog};
+use connection::{ConnectionSettings, ZkClient};
+use error::{Error, Result};
+use guard::{confirm, is_within, protected_violations};
+use journal::{read_journal, Journal, JournalEntry};
+use progress::Progress;
+use report::{Json, Outcome, Report};
+use traversal::{fetch_changed_znode, fetch_znode, traverse, traverse_with, Base, FetchedZnode, Zxids};
+
+/// Restore reads this many entries of the archive, or entries with this much data, before writing them.
+const RESTORE_BATCH_ENTRIES: usize = 10_000;
+const RESTORE_BATCH_BYTES: usize = 64 * 1024 * 1024;
+/// How many times dump re-reads the znodes changed while it walked the trees before it gives up on a consistent cut.
+const FENCE_ROUNDS: usize = 3;
+/// How often delete checks whether the ephemeral znodes it waits for are gone.
+const EPHEMERAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often watch checks the session while no change comes in.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// State of a znode captured before any mutation. With version checks enabled a write
+/// only succeeds if the znode is still in this state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlannedState {
+    Absent,
+    Version(i32),
+}
+
+/// What an operation does once it fails on a znode.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailurePolicy {
+    /// Abort the run
+    #[default]
+    FailFast,
+    /// Record the failure, carry on with the rest of the znodes and fail the run at the end
+    KeepGoing,
+}
+
+/// What `restore` does with znodes which were created as sequential ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SequentialPolicy {
+    /// Create them as ordinary persistent znodes keeping their names
+    #[default]
+    Preserve,
+    /// Create the children of `RestoreOptions::sequential_parents` as sequential znodes again in the original
+    /// order, their names may change. Others are preserved, as sequential znodes can't be told apart by name.
+    Recreate,
+}
+
+/// What `delete` does with trees containing ephemeral znodes, which only go away with their sessions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EphemeralPolicy {
+    /// Fail without deleting anything
+    #[default]
+    Abort,
+    /// Delete the rest of the trees, leaving the ephemeral znodes and their ancestors in place
+    SkipAncestors,
+    /// Wait up to this long for their sessions to expire, then abort if any is left
+    Wait(Duration),
+}
+
+/// Optional behaviour of `dump`.
+#[derive(Clone, Debug, Default)]
+pub struct DumpOptions {
+    /// Record ephemeral znodes along with their owner sessions, restore never creates them
+    pub include_ephemeral: bool,
+    /// Only record the changes since this archive, to be restored on top of it
+    pub base_file: Option<String>,
+    /// How many znodes to fetch concurrently
+    pub parallelism: usize,
+    /// Report neither progress nor summary
+    pub quiet: bool,
+    /// Write a JSON report of the run into this file
+    pub report_file: Option<String>,
+    pub failure_policy: FailurePolicy,
+}
+
+/// Behaviour of `watch` on top of the one of `dump`.
+#[derive(Clone, Debug, Default)]
+pub struct WatchOptions {
+    /// Append the changes seen since the last compaction to this file
+    pub change_log_file: String,
+    /// How often the change log is compacted into the archive
+    pub compact_interval: Duration,
+}
+
+/// Optional behaviour of `restore`.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Leave znodes changed by someone else since planning untouched
+    pub check_versions: bool,
+    /// Dump the restored trees into this file before changing anything
+    pub backup_file: Option<String>,
+    /// Record every change into this file, so that it can be rolled back
+    pub journal_file: Option<String>,
+    pub sequential_policy: SequentialPolicy,
+    /// Znodes whose children flagged as sequential in the archive are recreated by `SequentialPolicy::Recreate`
+    pub sequential_parents: Vec<String>,
+    /// Print ephemeral znodes found in the archive, they are never restored
+    pub list_ephemeral: bool,
+    /// How many znodes to write concurrently
+    pub parallelism: usize,
+    /// Report neither progress nor summary
+    pub quiet: bool,
+    /// Write a JSON report of the run into this file
+    pub report_file: Option<String>,
+    pub failure_policy: FailurePolicy,
+    /// Ask on the terminal before overwriting or deleting existing znodes
+    pub confirm: bool,
+    /// Znodes whose subtrees are never overwritten
+    pub protected_znodes: Vec<String>,
+}
+
+/// Optional behaviour of `delete`.
+#[derive(Clone, Debug, Default)]
+pub struct DeleteOptions {
+    /// Leave znodes changed by someone else since planning untouched
+    pub check_versions: bool,
+    /// Fail without deleting anything if any of the root znodes doesn't exist
+    pub require_existing: bool,
+    pub ephemeral_policy: EphemeralPolicy,
+    /// Dump the deleted trees into this file before changing anything
+    pub backup_file: Option<String>,
+    /// Record every change into this file, so that it can be rolled back
+    pub journal_file: Option<String>,
+    /// Report neither progress nor summary of the backup
+    pub quiet: bool,
+    /// Write a JSON report of the run into this file
+    pub report_file: Option<String>,
+    pub failure_policy: FailurePolicy,
+    /// Ask on the terminal before deleting anything
+    pub confirm: bool,
+    /// Znodes whose subtrees are never deleted
+    pub protected_znodes: Vec<String>,
+}
+
+pub fn dump(connection: &ConnectionSettings, znode_paths: Vec<&str>, dump_file: &str, excluded_znodes: Vec<&str>, options: DumpOptions) -> Outcome {
+    let mut parameters = report_parameters(connection, &znode_paths, &excluded_znodes);
+    parameters.extend(vec![
+        ("file", Json::from(dump_file)),
+        ("include_ephemeral", Json::from(options.include_ephemeral)),
+        ("incremental_from", Json::from(options.base_file.clone())),
+        ("parallelism", Json::from(options.parallelism)),
+        ("keep_going", Json::from(options.failure_policy == FailurePolicy::KeepGoing)),
+    ]);
+    let report = Report::start("dump", parameters);
+    let zk_client = ZkClient::connect(connection);
+    let mut outcome = Outcome::new(&znode_paths);
+    stop_on_absent_roots(&zk_client, &znode_paths, &mut outcome);
+    if !outcome.is_stopped() {
+        let mut progress = Progress::new(options.quiet);
+        outcome.merge(dump_znode_tree(&zk_client, &znode_paths, dump_file, &excluded_znodes, &options, &mut progress));
+        progress.finish("Dumped", &outcome.total());
+    }
+    let archive_file = if outcome.is_stopped() { None } else { Some(dump_file) };
+    write_report(report, &options.report_file, &outcome, archive_file);
+    outcome
+}
 
-pub fn dump(servers: &str, znode_paths: Vec<&str>, dump_file: &str, excluded_znodes: Vec<&str>) {
-    let zk_client = ZooKeeper::connect(servers, Duration::from_secs(15), |_| {}).unwrap();
-    zk_client.exists("/", false).expect("Connection failed");
+/// Stops on the first root znode which is absent or can't be checked.
+fn stop_on_absent_roots(zk_client: &ZkClient, znode_paths: &[&str], outcome: &mut Outcome) {
+    for znode_path in znode_paths {
+        match zk_client.exists(znode_path, false) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                error!(event = "absent", znode = *znode_path; "Expected znode is absent");
+                return outcome.stop(znode_path, ZkError::NoNode);
+            }
+            Err(err) => {
+                error!(event = "failed", znode = *znode_path, reason:% = err; "Can't read znode");
+                return outcome.stop(znode_path, err);
+            }
+        }
+    }
+}
+
+/// Keeps the archive up to date: dumps the trees, then follows their changes with watches, appending them
+/// to the change log and compacting it into the archive every `compact_interval`. Watches are set before
+/// the dump, so that nothing changed meanwhile is missed. Runs until the session is lost or a change
+/// can't be followed, as watches don't outlive either.
+pub fn watch(connection: &ConnectionSettings, znode_paths: Vec<&str>, dump_file: &str, excluded_znodes: Vec<&str>, options: DumpOptions,
+             watch_options: WatchOptions) -> Outcome {
+    let mut parameters = report_parameters(connection, &znode_paths, &excluded_znodes);
+    parameters.extend(vec![
+        ("file", Json::from(dump_file)),
+        ("include_ephemeral", Json::from(options.include_ephemeral)),
+        ("change_log", Json::from(watch_options.change_log_file.as_str())),
+        ("compact_every_ms", Json::from(watch_options.compact_interval.as_millis() as usize)),
+    ]);
+    let report = Report::start("watch", parameters);
+    let zk_client = ZkClient::connect(connection);
+    let (sender, events) = mpsc::channel();
+    let mut tree_watch = TreeWatch { zk_client: &zk_client, excluded_znodes: &excluded_znodes, include_ephemeral: options.include_ephemeral,
+                                     sender, watched: HashSet::new(), change_log: None };
+    let mut outcome = Outcome::new(&znode_paths);
+    stop_on_absent_roots(&zk_client, &znode_paths, &mut outcome);
     for znode_path in &znode_paths {
-        if zk_client.exists(*znode_path, false).unwrap().is_none() {
-            panic!("Expected znode is absent: {}", *znode_path);
+        if outcome.is_stopped() {
+            break;
         }
+        if let Err(err) = tree_watch.watch_subtree(znode_path) {
+            error!(event = "failed", znode = *znode_path, reason:% = err; "Can't watch znode");
+            outcome.stop(znode_path, err);
+        }
+    }
+    if !outcome.is_stopped() {
+        let mut progress = Progress::new(options.quiet);
+        outcome.merge(dump_znode_tree(&zk_client, &znode_paths, dump_file, &excluded_znodes, &options, &mut progress));
+        progress.finish("Dumped", &outcome.total());
     }
-    for tree_root_znode_path in znode_paths {
-        dump_znode_tree(&zk_client, tree_root_znode_path, dump_file, &excluded_znodes);
+    if outcome.is_stopped() {
+        write_report(report, &options.report_file, &outcome, None);
+        return outcome;
     }
+    let change_log_file = watch_options.change_log_file.as_str();
+    tree_watch.change_log = Some(ChangeLog::create(change_log_file)
+        .unwrap_or_else(|err| panic!("Can't create change log '{}'. Reason: '{}'", change_log_file, err)));
+    info!(event = "watching", znodes = tree_watch.watched.len(); "Watching for changes");
+    let mut next_compaction = Instant::now() + watch_options.compact_interval;
+    let stopped = loop {
+        let timeout = next_compaction.saturating_duration_since(Instant::now()).min(WATCH_POLL_INTERVAL);
+        let result = match events.recv_timeout(timeout) {
+            Ok(event) => tree_watch.on_event(event),
+            Err(_) => zk_client.check_session().map_err(Error::from),
+        };
+        if let Err(err) = result {
+            break err;
+        }
+        if Instant::now() >= next_compaction {
+            next_compaction = Instant::now() + watch_options.compact_interval;
+            if let Err(err) = compact_change_log(dump_file, change_log_file, tree_watch.change_log.as_mut().unwrap()) {
+                break err;
+            }
+        }
+    };
+    error!(event = "stopped", reason:% = stopped; "Watch stopped, changes recorded so far are compacted into the archive");
+    outcome.fail(znode_paths[0], stopped);
+    if let Err(err) = compact_change_log(dump_file, change_log_file, tree_watch.change_log.as_mut().unwrap()) {
+        outcome.fail(znode_paths[0], err);
+    }
+    write_report(report, &options.report_file, &outcome, Some(dump_file));
+    outcome
 }
 
-pub fn restore(servers: &str, dump_file: &str, znode_paths: Vec<&str>, excluded_znodes: Vec<&str>) {
-    let zk_client = ZooKeeper::connect(servers, Duration::from_secs(15), |_| {}).unwrap();
-    zk_client.exists("/", false).expect("Connection failed");
-    let tar_gz = File::open(dump_file).expect("Can't read tar file");
-    let tar = GzDecoder::new(tar_gz);
-    let mut archive = Archive::new(tar);
+/// Watched znodes of the trees along with where their changes go.
+struct TreeWatch<'a> {
+    zk_client: &'a ZkClient,
+    excluded_znodes: &'a Vec<&'a str>,
+    include_ephemeral: bool,
+    sender: Sender<WatchedEvent>,
+    watched: HashSet<String>,
+    /// Changes aren't recorded until the initial dump is written
+    change_log: Option<ChangeLog>,
+}
+
+impl<'a> TreeWatch<'a> {
+    /// Sets data and child watches on every znode of the subtree not watched yet, recording it as changed.
+    fn watch_subtree(&mut self, root_znode_path: &str) -> Result<()> {
+        let mut pending = vec![String::from(root_znode_path)];
+        while let Some(znode_path) = pending.pop() {
+            if self.watched.contains(&znode_path) || is_excluded(&znode_path, self.excluded_znodes) {
+                continue;
+            }
+            let (data, stat) = match self.zk_client.get_data_w(&znode_path, self.watcher()) {
+                Ok(found) => found,
+                Err(ZkError::NoNode) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if Owner::from_ephemeral_owner(stat.ephemeral_owner).is_ephemeral() && !self.include_ephemeral {
+                continue;
+            }
+            let children = match self.zk_client.get_children_w(&znode_path, self.watcher()) {
+                Ok(children) => children,
+                Err(ZkError::NoNode) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let parent_path = ensure_ends_with_slash(&znode_path);
+            pending.extend(children.iter().map(|child| parent_path.clone() + child));
+            self.watched.insert(znode_path.clone());
+            let meta = stat_meta(&znode_path, &stat);
+            self.record(Change::Changed { path: znode_path, meta, data })?;
+        }
+        Ok(())
+    }
+
+    /// Records what a fired watch tells and sets it again.
+    fn on_event(&mut self, event: WatchedEvent) -> Result<()> {
+        let znode_path = match event.path {
+            Some(znode_path) if self.watched.contains(&znode_path) => znode_path,
+            _ => return Ok(()),
+        };
+        match event.event_type {
+            WatchedEventType::NodeDataChanged => match self.zk_client.get_data_w(&znode_path, self.watcher()) {
+                Ok((data, stat)) => {
+                    let meta = stat_meta(&znode_path, &stat);
+                    self.record(Change::Changed { path: znode_path, meta, data })
+                }
+                Err(ZkError::NoNode) => self.forget(znode_path),
+                Err(err) => Err(err.into()),
+            },
+            WatchedEventType::NodeChildrenChanged => match self.zk_client.get_children_w(&znode_path, self.watcher()) {
+                Ok(children) => {
+                    let parent_path = ensure_ends_with_slash(&znode_path);
+                    children.iter().try_for_each(|child| self.watch_subtree(&(parent_path.clone() + child)))
+                }
+                Err(ZkError::NoNode) => self.forget(znode_path),
+                Err(err) => Err(err.into()),
+            },
+            WatchedEventType::NodeDeleted => self.forget(znode_path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a deleted znode once, as both of its watches fire. The deletion is at the zxid the children
+    /// of its parent last changed at, unless the parent changed again since, which gets recorded as well.
+    fn forget(&mut self, znode_path: String) -> Result<()> {
+        if self.watched.remove(&znode_path) {
+            let zxid = match parent_path(&znode_path) {
+                Some(parent_path) => self.zk_client.exists(parent_path, false)?.map(|stat| stat.pzxid),
+                None => None,
+            };
+            self.record(Change::Deleted { path: znode_path, zxid })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn record(&mut self, change: Change) -> Result<()> {
+        if let Some(ref mut change_log) = self.change_log {
+            info!(event = "recorded", znode = change.path(); "Change recorded");
+            change_log.record(&change)?;
+        }
+        Ok(())
+    }
+
+    fn watcher(&self) -> impl Fn(WatchedEvent) + Clone + Send + 'static {
+        let sender = self.sender.clone();
+        move |event| {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+/// Applies the recorded changes to the archive and empties the change log.
+fn compact_change_log(dump_file: &str, change_log_file: &str, change_log: &mut ChangeLog) -> Result<()> {
+    let changes = read_change_log(change_log_file)?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let count = changes.len();
+    compact(dump_file, changes)?;
+    change_log.truncate()?;
+    info!(event = "compacted", changes = count; "Change log compacted into the archive");
+    Ok(())
+}
+
+/// Rewrites the archive with the last change of every znode applied, changed znodes follow the untouched ones.
+/// The manifest moves on to the last zxid applied, keeps the base of an incremental archive and counts the znodes anew.
+fn compact(dump_file: &str, changes: Vec<Change>) -> Result<()> {
+    let mut latest = BTreeMap::new();
+    let mut applied_zxid = None;
+    for change in changes {
+        applied_zxid = applied_zxid.max(change.zxid());
+        latest.insert(String::from(change.path()), change);
+    }
+    write_archive(dump_file, |tar_archive| {
+        let mut manifest = Manifest::default();
+        let mut znodes = 0;
+        let mut archive = open_dump_file(dump_file);
+        for file in archive.entries()? {
+            let mut file = file?;
+            let tar_path = String::from(file.path()?.to_str().unwrap());
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            if tar_path == MANIFEST_FILE_NAME {
+                manifest = Manifest::parse(&content)?;
+            } else if !latest.contains_key(&tar_path_to_znode_path(&tar_path)) {
+                if tar_path.ends_with(META_FILE_NAME) && ZnodeMeta::parse(&content)?.mode != ZnodeMode::Deleted {
+                    znodes += 1;
+                }
+                write_tar_entry(&tar_path, content, tar_archive)?;
+            }
+        }
+        for change in latest.into_values() {
+            if let Change::Changed { path, meta, data } = change {
+                znodes += 1;
+                write_tar_entry(znode_path_to_meta_tar_path(&path).as_str(), meta.to_bytes(), tar_archive)?;
+                write_tar_entry(znode_path_to_tar_path(&path).as_str(), data, tar_archive)?;
+            }
+        }
+        let manifest = Manifest { zxid: manifest.zxid.max(applied_zxid), znodes: Some(znodes), ..manifest };
+        write_tar_entry(MANIFEST_FILE_NAME, manifest.to_bytes(), tar_archive)
+    })
+}
+
+pub fn restore(connection: &ConnectionSettings, dump_file: &str, znode_paths: Vec<&str>, excluded_znodes: Vec<&str>, options: RestoreOptions) -> Outcome {
+    let mut parameters = report_parameters(connection, &znode_paths, &excluded_znodes);
+    parameters.extend(vec![
+        ("file", Json::from(dump_file)),
+        ("check_versions", Json::from(options.check_versions)),
+        ("backup_before", Json::from(options.backup_file.clone())),
+        ("journal", Json::from(options.journal_file.clone())),
+        ("sequential", Json::from(format!("{:?}", options.sequential_policy).to_lowercase())),
+        ("sequential_parents", paths_parameter(&options.sequential_parents)),
+        ("parallelism", Json::from(options.parallelism)),
+        ("keep_going", Json::from(options.failure_policy == FailurePolicy::KeepGoing)),
+        ("protected", paths_parameter(&options.protected_znodes)),
+    ]);
+    let report = Report::start("restore", parameters);
+    let mut outcome = Outcome::new(&znode_paths);
+    if fail_on_protected(&znode_paths, &excluded_znodes, &options.protected_znodes, &mut outcome) {
+        write_report(report, &options.report_file, &outcome, Some(dump_file));
+        return outcome;
+    }
+    let zk_client = ZkClient::connect(connection);
+    let plan = if options.check_versions || options.confirm {
+        Some(plan_restore(&zk_client, dump_file, &znode_paths, &excluded_znodes, options.parallelism, &mut outcome))
+    } else {
+        None
+    };
+    if outcome.is_stopped() {
+        write_report(report, &options.report_file, &outcome, Some(dump_file));
+        return outcome;
+    }
+    if let Some(plan) = plan.as_ref().filter(|_| options.confirm) {
+        // Only znodes which are there already are at stake, adding new ones needs no confirmation
+        let existing = plan.states.values().filter(|state| **state != PlannedState::Absent).count();
+        let question = format!("Restore {} znodes from '{}' into {}, overwriting {} existing ones and deleting {} subtrees?",
+                               plan.states.len(), dump_file, quoted(&znode_paths), existing, plan.tombstones);
+        if (existing > 0 || plan.tombstones > 0) && !confirm(&question) {
+            outcome.abort();
+            write_report(report, &options.report_file, &outcome, Some(dump_file));
+            return outcome;
+        }
+    }
+    if let Some(ref backup_file) = options.backup_file {
+        backup_before_mutation(&zk_client, &znode_paths, backup_file, &excluded_znodes, options.parallelism, options.quiet, &mut outcome);
+        if outcome.is_stopped() {
+            write_report(report, &options.report_file, &outcome, Some(dump_file));
+            return outcome;
+        }
+    }
+    let journal = open_journal(&options.journal_file);
+    if let Some(ref chroot) = connection.chroot {
+        if let Err(err) = create_chroot(connection, chroot, &journal) {
+            error!(event = "failed", chroot = chroot.as_str(), reason:% = err; "Can't create chroot");
+            outcome.stop("/", format!("Can't create chroot '{}'. Reason: '{}'", chroot, err));
+            write_report(report, &options.report_file, &outcome, Some(dump_file));
+            return outcome;
+        }
+    }
+    let outcome = apply_restore(&zk_client, dump_file, &znode_paths, &excluded_znodes, &options, plan.as_ref(), &journal, outcome);
+    write_report(report, &options.report_file, &outcome, Some(dump_file));
+    outcome
+}
+
+/// Writes the archived znodes, leaving those whose state differs from the planned one untouched if versions are checked.
+/// The archive is read an extra time beforehand to find the entries superseded by later ones.
+#[allow(clippy::too_many_arguments)]
+fn apply_restore(zk_client: &ZkClient, dump_file: &str, znode_paths: &Vec<&str>, excluded_znodes: &Vec<&str>, options: &RestoreOptions,
+                 plan: Option<&RestorePlan>, journal: &Journal, outcome: Outcome) -> Outcome {
+    let latest = latest_entries(dump_file, znode_paths, excluded_znodes);
+    let mut progress = Progress::new(options.quiet);
+    progress.expect(latest.count());
+    let planned_states = plan.filter(|_| options.check_versions).map(|plan| &plan.states);
+    let mut state = RestoreState { outcome, ..Default::default() };
+    let read_outcome = read_restore_entries(dump_file, znode_paths, excluded_znodes, zk_client.chroot(), options.list_ephemeral, &latest, |entries| {
+        restore_batch(zk_client, entries, options, planned_states, journal, &mut state, &mut progress);
+        !state.outcome.is_stopped()
+    });
+    state.outcome.merge(read_outcome);
+    report_conflicts(&state.outcome);
+    let mut recreated: Vec<(&String, &String)> = state.recreated.iter().collect();
+    recreated.sort();
+    for (znode_path, created_path) in recreated {
+        warn!(event = "recreated", znode = znode_path.as_str(), created = created_path.as_str(); "Sequential znode recreated under a new name");
+    }
+    progress.finish("Restored", &state.outcome.total());
+    state.outcome
+}
+
+/// What happened so far during `restore`, carried from one batch of entries to the next.
+#[derive(Default)]
+struct RestoreState {
+    /// Znodes known to exist, so that their creation isn't attempted again
+    ensured_paths: HashSet<String>,
+    /// Paths sequential znodes were recreated with by their archived paths
+    recreated: HashMap<String, String>,
+    /// Archived paths of TTL znodes, which aren't restored along with their subtrees
+    rejected: Vec<String>,
+    outcome: Outcome,
+}
+
+/// How a znode was written by `restore`.
+#[derive(Debug, PartialEq)]
+enum Restored {
+    Created,
+    Updated,
+    /// Created as a sequential znode again with this path
+    Recreated(String),
+}
+
+/// Writes entries level by level, so that parents always exist before their children are written,
+/// then deletes the subtrees of tombstones. Nothing more is written once the outcome is stopped.
+fn restore_batch(zk_client: &ZkClient, entries: Vec<RestoreEntry>, options: &RestoreOptions, planned_states: Option<&HashMap<String, PlannedState>>,
+                 journal: &Journal, state: &mut RestoreState, progress: &mut Progress) {
+    let (tombstones, entries): (Vec<RestoreEntry>, Vec<RestoreEntry>) = entries.into_iter()
+        .partition(|entry| entry.meta.mode == ZnodeMode::Deleted);
+    for level in restore_levels(entries, options) {
+        let mut tasks: Vec<Vec<RestoreTask>> = Vec::new();
+        for entries in level {
+            let mut group = Vec::new();
+            for entry in entries {
+                if state.rejected.iter().any(|rejected| is_within(&entry.znode_path, rejected)) {
+                    progress.advance(1, entry.data.len());
+                    info!(event = "skipped", znode = entry.znode_path.as_str(), reason = "ttl"; "Znode skipped");
+                    state.outcome.of(&entry.znode_path).skipped += 1;
+                    continue;
+                }
+                if let Some(ttl) = entry.meta.ttl {
+                    progress.advance(1, entry.data.len());
+                    error!(event = "failed", znode = entry.znode_path.as_str(), ttl_ms = ttl; "Can't restore TTL znode as the client doesn't support TTL");
+                    let reason = "TTL znodes can't be created by the client";
+                    if options.failure_policy == FailurePolicy::FailFast {
+                        state.outcome.stop(&entry.znode_path, reason);
+                        return;
+                    }
+                    state.outcome.fail(&entry.znode_path, reason);
+                    state.rejected.push(entry.znode_path);
+                    continue;
+                }
+                let target_path = renamed_path(&entry.znode_path, &state.recreated);
+                if let Err(err) = ensure_parents(zk_client, &target_path, &mut state.ensured_paths, journal) {
+                    progress.advance(1, entry.data.len());
+                    record_failure(&mut state.outcome, options.failure_policy, "restore", &target_path, err);
+                    if state.outcome.is_stopped() {
+                        return;
+                    }
+                    continue;
+                }
+                let planned_state = match planned_states {
+                    Some(states) if target_path == entry.znode_path => Some(states[&entry.znode_path]),
+                    Some(_) => Some(PlannedState::Absent),
+                    None => None,
+                };
+                group.push(RestoreTask { entry, target_path, planned_state });
+            }
+            tasks.push(group);
+        }
+        let results = run_concurrently(tasks, options.parallelism, |tasks| tasks.into_iter()
+            .map(|task| {
+                let result = restore_znode(zk_client, &task, options, journal);
+                (task, result)
+            })
+            .collect::<Vec<_>>());
+        for (task, result) in results.into_iter().flatten() {
+            progress.advance(1, task.entry.data.len());
+            match result {
+                Ok(Restored::Created) => {
+                    info!(event = "created", znode = task.target_path.as_str(); "Znode created");
+                    state.outcome.of(&task.entry.znode_path).created += 1;
+                    state.ensured_paths.insert(task.target_path);
+                }
+                Ok(Restored::Updated) => {
+                    info!(event = "updated", znode = task.target_path.as_str(); "Znode updated");
+                    state.outcome.of(&task.entry.znode_path).updated += 1;
+                    state.ensured_paths.insert(task.target_path);
+                }
+                Ok(Restored::Recreated(created_path)) => {
+                    info!(event = "created", znode = created_path.as_str(), archived_as = task.entry.znode_path.as_str(); "Znode created");
+                    state.outcome.of(&task.entry.znode_path).created += 1;
+                    state.ensured_paths.insert(created_path.clone());
+                    state.recreated.insert(task.entry.znode_path, created_path);
+                }
+                Err(ref err) if planned_states.is_some() && is_version_conflict(err) => {
+                    warn!(event = "failed", znode = task.target_path.as_str(), reason:% = err; "Znode modified since planning");
+                    state.outcome.conflict(&task.target_path, err);
+                }
+                Err(err) => record_failure(&mut state.outcome, options.failure_policy, "restore", &task.target_path, err),
+            }
+        }
+        if state.outcome.is_stopped() {
+            return;
+        }
+    }
+    for tombstone in tombstones {
+        let target_path = renamed_path(&tombstone.znode_path, &state.recreated);
+        progress.advance(1, 0);
+        match delete_subtree(zk_client, &target_path, journal) {
+            Ok(deleted) => {
+                info!(event = "deleted", znode = target_path.as_str(), znodes = deleted; "Znode deleted");
+                state.outcome.of(&tombstone.znode_path).deleted += deleted;
+                state.ensured_paths.retain(|ensured_path| !is_within(ensured_path, &target_path));
+            }
+            Err(err) => record_failure(&mut state.outcome, options.failure_policy, "delete", &target_path, err),
+        }
+        if state.outcome.is_stopped() {
+            return;
+        }
+    }
+}
+
+/// Deletes a znode along with its subtree, children first, and returns how many znodes were there.
+fn delete_subtree(zk_client: &ZkClient, znode_path: &str, journal: &Journal) -> ZkResult<usize> {
+    let children = match zk_client.get_children(znode_path, false) {
+        Ok(children) => children,
+        Err(ZkError::NoNode) => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let current_path = ensure_ends_with_slash(znode_path);
+    let mut deleted = 0;
+    for child in children {
+        deleted += delete_subtree(zk_client, &(current_path.clone() + &child), journal)?;
+    }
+    match journal_current_state(zk_client, znode_path, journal, true).and_then(|_| zk_client.delete(znode_path, None)) {
+        Ok(_) => Ok(deleted + 1),
+        Err(ZkError::NoNode) => Ok(deleted),
+        Err(err) => Err(err),
+    }
+}
+
+/// Creates empty znodes for the ancestors of a znode, which aren't known to exist yet.
+/// Fails with the error of the first ancestor that could neither be created nor found.
+fn ensure_parents(zk_client: &ZkClient, znode_path: &str, ensured_paths: &mut HashSet<String>, journal: &Journal) -> ZkResult<()> {
+    for ancestor in unensured_ancestors(znode_path, ensured_paths) {
+        match zk_client.create(&ancestor, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent) {
+            Ok(_) => journal.record(&JournalEntry::Created { path: ancestor.clone() }).expect("Can't write journal"),
+            Err(ZkError::NodeExists) => {}
+            Err(err) => return Err(err),
+        }
+        ensured_paths.insert(ancestor);
+    }
+    Ok(())
+}
+
+/// Ancestors of a znode not in `ensured_paths`, the root first.
+fn unensured_ancestors(znode_path: &str, ensured_paths: &HashSet<String>) -> Vec<String> {
+    znode_path.match_indices('/')
+        .map(|(index, _)| &znode_path[..index])
+        .filter(|ancestor| !ancestor.is_empty() && !ensured_paths.contains(*ancestor))
+        .map(String::from)
+        .collect()
+}
+
+/// A znode of the archive selected for restore.
+struct RestoreEntry {
+    znode_path: String,
+    data: Vec<u8>,
+    meta: ZnodeMeta,
+}
+
+/// A restore entry together with everything decided about it before it is written.
+struct RestoreTask {
+    entry: RestoreEntry,
+    target_path: String,
+    planned_state: Option<PlannedState>,
+}
+
+/// Reads the znodes selected for restore, skipping ephemeral ones and entries superseded according to `latest`,
+/// and passes them to `restore` in bounded batches, so that the whole archive is never held in memory.
+/// Reading stops once `restore` returns false. Returns how many znodes of every root were skipped and excluded.
+fn read_restore_entries<F>(dump_file: &str, znode_paths: &Vec<&str>, excluded_znodes: &Vec<&str>, chroot: Option<&str>,
+                           list_ephemeral: bool, latest: &LatestEntries, mut restore: F) -> Outcome where F: FnMut(Vec<RestoreEntry>) -> bool {
+    let mut restore_entries = Vec::new();
+    let mut batch_bytes = 0;
+    let mut ordinal = 0;
+    let mut outcome = Outcome::new(znode_paths);
+    let mut pending_meta: Option<(String, ZnodeMeta)> = None;
+    let mut archive = open_dump_file(dump_file);
     let entries = archive.entries().expect("Can't unpack tar file");
     for file in entries {
         let mut file = file.unwrap();
         let mut data: Vec<u8> = Vec::new();
         file.read_to_end(&mut data).unwrap();
         let path = file.path().unwrap();
-        let znode_path = tar_path_to_znode_path(path.to_str().unwrap());
-        let is_excluded = excluded_znodes.iter()
-            .any(|excluded| znode_path.starts_with(excluded));
-        let is_for_restoring = znode_paths.iter()
-            .any(|for_restoring| znode_path.starts_with(for_restoring));
-        if !is_excluded && is_for_restoring {
-            create_znodes_for_path(&zk_client, znode_path.as_str(), data);
+        let tar_path = path.to_str().unwrap();
+        if tar_path == MANIFEST_FILE_NAME {
+            let manifest = Manifest::parse(&data).unwrap_or_else(|err| panic!("Can't read manifest of '{}'. Reason: '{}'", dump_file, err));
+            if manifest.chroot.as_deref() != chroot {
+                warn!(event = "chroot", dumped = manifest.chroot.as_deref().unwrap_or("/"), restored = chroot.unwrap_or("/");
+                      "Znodes dumped from another chroot are restored into this one");
+            }
+            if let Some(base_zxid) = manifest.base_zxid {
+                warn!(event = "incremental", base_zxid = format_zxid(base_zxid).as_str();
+                      "Archive only holds the changes since its base archive, which is expected to be restored first");
+            }
+            continue;
+        }
+        let znode_path = tar_path_to_znode_path(tar_path);
+        if tar_path.ends_with(META_FILE_NAME) {
+            let meta = ZnodeMeta::parse(&data).unwrap_or_else(|err| panic!("Can't read metadata of znode '{}'. Reason: '{}'", znode_path, err));
+            pending_meta = Some((znode_path, meta));
+            continue;
+        }
+        let meta = match pending_meta.take() {
+            Some((meta_znode_path, meta)) if meta_znode_path == znode_path => meta,
+            _ => ZnodeMeta::default(),
+        };
+        debug!(event = "visited", znode = znode_path.as_str(); "Znode visited");
+        if !is_selected_for_restore(&znode_path, znode_paths, excluded_znodes) {
+            let is_under_root = znode_paths.iter().any(|for_restoring| is_within(&znode_path, for_restoring));
+            if is_under_root && is_excluded(&znode_path, excluded_znodes) {
+                info!(event = "excluded", znode = znode_path.as_str(); "Znode excluded");
+                outcome.of(&znode_path).excluded += 1;
+            }
+            continue;
+        }
+        ordinal += 1;
+        if latest.is_superseded(&znode_path, ordinal - 1) {
+            debug!(event = "superseded", znode = znode_path.as_str(); "Znode archived again later");
+            continue;
+        }
+        if meta.mode == ZnodeMode::Ephemeral {
+            info!(event = "skipped", znode = znode_path.as_str(), reason = "ephemeral"; "Znode skipped");
+            outcome.of(&znode_path).skipped += 1;
+            if list_ephemeral {
+                let session = meta.session.map(format_session).unwrap_or_default();
+                warn!(event = "ephemeral", znode = znode_path.as_str(), session = session.as_str(); "Ephemeral znode was not restored");
+            }
+            continue;
+        }
+        batch_bytes += data.len();
+        restore_entries.push(RestoreEntry { znode_path, data, meta });
+        if restore_entries.len() >= RESTORE_BATCH_ENTRIES || batch_bytes >= RESTORE_BATCH_BYTES {
+            if !restore(mem::take(&mut restore_entries)) {
+                return outcome;
+            }
+            batch_bytes = 0;
         }
     }
+    if !restore_entries.is_empty() {
+        restore(restore_entries);
+    }
+    outcome
 }
 
-pub fn delete(servers: &str, znode_paths: Vec<&str>, excluded_znodes: Vec<&str>) {
-    let zk_client = ZooKeeper::connect(servers, Duration::from_secs(15), |_| {}).unwrap();
-    zk_client.exists("/", false).expect("Connection failed");
-    for znode_path in &znode_paths {
-        if zk_client.exists(*znode_path, false).unwrap().is_none() {
+/// Groups entries by depth, so that every level can be written concurrently once the previous one
+/// is done. Within a level every group is written serially by one worker: sequential znodes to be
+/// recreated share a group with their sequential siblings to get counters in the original order.
+fn restore_levels(entries: Vec<RestoreEntry>, options: &RestoreOptions) -> Vec<Vec<Vec<RestoreEntry>>> {
+    let mut levels: BTreeMap<usize, Vec<Vec<RestoreEntry>>> = BTreeMap::new();
+    let mut sequential_groups: HashMap<String, usize> = HashMap::new();
+    for entry in entries {
+        let depth = if entry.znode_path == "/" { 0 } else { entry.znode_path.matches('/').count() };
+        let level = levels.entry(depth).or_default();
+        let is_recreated = is_recreated(&entry, options);
+        let parent = String::from(parent_path(&entry.znode_path).unwrap_or(""));
+        match sequential_groups.get(&parent) {
+            Some(&group) if is_recreated => level[group].push(entry),
+            _ => {
+                if is_recreated {
+                    sequential_groups.insert(parent, level.len());
+                }
+                level.push(vec![entry]);
+            }
+        }
+    }
+    levels.into_values().collect()
+}
+
+fn parent_path(znode_path: &str) -> Option<&str> {
+    match znode_path.rfind('/') {
+        Some(0) if znode_path.len() > 1 => Some("/"),
+        Some(0) | None => None,
+        Some(index) => Some(&znode_path[..index]),
+    }
+}
+
+/// Whether an entry is created as a sequential znode again: it has to be flagged as one in the archive
+/// and its parent has to be named as holding sequential znodes.
+fn is_recreated(entry: &RestoreEntry, options: &RestoreOptions) -> bool {
+    options.sequential_policy == SequentialPolicy::Recreate
+        && entry.meta.mode == ZnodeMode::PersistentSequential
+        && parent_path(&entry.znode_path).is_some_and(|parent| options.sequential_parents.iter().any(|sequential_parent| sequential_parent == parent))
+}
+
+fn restore_znode(zk_client: &ZkClient, task: &RestoreTask, options: &RestoreOptions, journal: &Journal) -> ZkResult<Restored> {
+    let entry = &task.entry;
+    if is_recreated(entry, options) {
+        create_sequential_znode(zk_client, task.target_path.as_str(), entry.data.clone(), &entry.meta, journal).map(Restored::Recreated)
+    } else {
+        create_znodes_for_path(zk_client, task.target_path.as_str(), entry.data.clone(), &entry.meta, task.planned_state, journal)
+    }
+}
+
+/// Applies `work` to every task using `parallelism` threads, results are in the order of the tasks.
+fn run_concurrently<T, R, F>(tasks: Vec<T>, parallelism: usize, work: F) -> Vec<R> where T: Send, R: Send, F: Fn(T) -> R + Sync {
+    let tasks = Mutex::new(tasks.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..parallelism.max(1) {
+            scope.spawn(|| loop {
+                let task = tasks.lock().unwrap().next();
+                match task {
+                    Some((index, task)) => {
+                        let result = work(task);
+                        results.lock().unwrap().push((index, result));
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter()
+        .map(|(_, result)| result)
+        .collect()
+}
+
+/// Creates the missing znodes of the chroot restore targets, as nothing can be created under an absent chroot.
+/// Created znodes are journaled with their paths outside the chroot.
+fn create_chroot(connection: &ConnectionSettings, chroot: &str, journal: &Journal) -> ZkResult<()> {
+    let zk_client = ZkClient::connect(&ConnectionSettings { chroot: None, ..connection.clone() });
+    let mut path = String::new();
+    for znode in chroot.split('/').skip(1) {
+        path = path + "/" + znode;
+        match zk_client.create(path.as_str(), vec![], Acl::open_unsafe().clone(), CreateMode::Persistent) {
+            Ok(_) => {
+                info!(event = "created", znode = path.as_str(); "Chroot znode created");
+                journal.record(&JournalEntry::ChrootCreated { path: path.clone() }).expect("Can't write journal");
+            }
+            Err(ZkError::NodeExists) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Applies renames of already recreated sequential znodes to the path of their descendant. Every created path
+/// has the renames of its ancestors applied already, so only the deepest recreated ancestor matters.
+fn renamed_path(znode_path: &str, recreated: &HashMap<String, String>) -> String {
+    znode_path.rmatch_indices('/')
+        .filter(|(index, _)| *index > 0)
+        .find_map(|(index, _)| recreated.get(&znode_path[..index]).map(|created_path| created_path.clone() + &znode_path[index..]))
+        .unwrap_or_else(|| String::from(znode_path))
+}
+
+/// What `restore` is about to change, captured before any mutation.
+struct RestorePlan {
+    /// States of the znodes to write
+    states: HashMap<String, PlannedState>,
+    /// How many subtrees are deleted
+    tombstones: usize,
+}
+
+/// Captures the versions of the znodes to restore, checking them concurrently in batches,
+/// and stops `outcome` if one can't be read.
+fn plan_restore(zk_client: &ZkClient, dump_file: &str, znode_paths: &Vec<&str>, excluded_znodes: &Vec<&str>, parallelism: usize,
+                outcome: &mut Outcome) -> RestorePlan {
+    let mut plan = RestorePlan { states: HashMap::new(), tombstones: 0 };
+    let mut unplanned = Vec::new();
+    visit_selected_modes(dump_file, znode_paths, excluded_znodes, |znode_path, mode| {
+        match mode {
+            ZnodeMode::Deleted => plan.tombstones += 1,
+            ZnodeMode::Ephemeral => {}
+            _ => unplanned.push(znode_path),
+        }
+        if unplanned.len() >= RESTORE_BATCH_ENTRIES {
+            plan_states(zk_client, mem::take(&mut unplanned), parallelism, &mut plan.states, outcome);
+        }
+        !outcome.is_stopped()
+    });
+    if !outcome.is_stopped() {
+        plan_states(zk_client, unplanned, parallelism, &mut plan.states, outcome);
+    }
+    plan
+}
+
+/// Last entries of the znodes selected for restore, found before restoring, as a dump archives znodes changed
+/// while dumping again at its end, which may be far more than a batch away from their first entries.
+/// Entries are numbered in archive order, counting only those selected for restore.
+#[derive(Default)]
+struct LatestEntries {
+    /// Number and mode of the last entry of every znode
+    last: HashMap<String, (usize, ZnodeMode)>,
+    /// Number of the last tombstone of every znode
+    tombstones: HashMap<String, usize>,
+}
+
+impl LatestEntries {
+    /// An entry is superseded by a later one of the same znode, or by a later tombstone of one of its ancestors.
+    fn is_superseded(&self, znode_path: &str, ordinal: usize) -> bool {
+        if self.last.get(znode_path).is_some_and(|(last, _)| *last > ordinal) {
+            return true;
+        }
+        let mut ancestor = parent_path(znode_path);
+        while let Some(ancestor_path) = ancestor {
+            if self.tombstones.get(ancestor_path).is_some_and(|tombstone| *tombstone > ordinal) {
+                return true;
+            }
+            ancestor = parent_path(ancestor_path);
+        }
+        false
+    }
+
+    /// How many znodes `restore` writes or deletes.
+    fn count(&self) -> usize {
+        self.last.iter()
+            .filter(|(znode_path, (ordinal, mode))| *mode != ZnodeMode::Ephemeral && !self.is_superseded(znode_path, *ordinal))
+            .count()
+    }
+}
+
+fn latest_entries(dump_file: &str, znode_paths: &Vec<&str>, excluded_znodes: &Vec<&str>) -> LatestEntries {
+    let mut latest = LatestEntries::default();
+    let mut ordinal = 0;
+    visit_selected_modes(dump_file, znode_paths, excluded_znodes, |znode_path, mode| {
+        if mode == ZnodeMode::Deleted {
+            latest.tombstones.insert(znode_path.clone(), ordinal);
+        }
+        latest.last.insert(znode_path, (ordinal, mode));
+        ordinal += 1;
+        true
+    });
+    latest
+}
+
+/// Passes every znode of the archive selected for restore to `visit` along with its mode, skipping the data,
+/// until `visit` returns false.
+fn visit_selected_modes<F>(dump_file: &str, znode_paths: &Vec<&str>, excluded_znodes: &Vec<&str>, mut visit: F) where F: FnMut(String, ZnodeMode) -> bool {
+    let mut pending_mode: Option<(String, ZnodeMode)> = None;
+    let mut archive = open_dump_file(dump_file);
+    let entries = archive.entries().expect("Can't unpack tar file");
+    for file in entries {
+        let mut file = file.unwrap();
+        let tar_path = String::from(file.path().unwrap().to_str().unwrap());
+        if tar_path == MANIFEST_FILE_NAME {
+            continue;
+        }
+        let znode_path = tar_path_to_znode_path(&tar_path);
+        if tar_path.ends_with(META_FILE_NAME) {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            let meta = ZnodeMeta::parse(&data).unwrap_or_else(|err| panic!("Can't read metadata of znode '{}'. Reason: '{}'", znode_path, err));
+            pending_mode = Some((znode_path, meta.mode));
+            continue;
+        }
+        let mode = match pending_mode.take() {
+            Some((meta_znode_path, mode)) if meta_znode_path == znode_path => mode,
+            _ => ZnodeMode::Persistent,
+        };
+        if is_selected_for_restore(&znode_path, znode_paths, excluded_znodes) && !visit(znode_path, mode) {
             return;
         }
     }
-    for tree_root_znode_path in znode_paths {
-        delete_znode_tree(&zk_client, tree_root_znode_path, &excluded_znodes);
+}
+
+fn plan_states(zk_client: &ZkClient, znode_paths: Vec<String>, parallelism: usize, states: &mut HashMap<String, PlannedState>, outcome: &mut Outcome) {
+    let stats = run_concurrently(znode_paths.clone(), parallelism, |znode_path| zk_client.exists(&znode_path, false));
+    for (znode_path, stat) in znode_paths.into_iter().zip(stats) {
+        let planned_state = match stat {
+            Ok(Some(stat)) => PlannedState::Version(stat.version),
+            Ok(None) => PlannedState::Absent,
+            Err(err) => return record_failure(outcome, FailurePolicy::FailFast, "read", &znode_path, err),
+        };
+        states.insert(znode_path, planned_state);
+    }
+}
+
+/// Dumps the existing ones of `znode_paths` into `backup_file`, stopping `outcome` before any
+/// mutation took place if that is not possible.
+fn backup_before_mutation(zk_client: &ZkClient, znode_paths: &Vec<&str>, backup_file: &str, excluded_znodes: &Vec<&str>, parallelism: usize,
+                          quiet: bool, outcome: &mut Outcome) {
+    let mut existing_roots = Vec::new();
+    for znode_path in znode_paths {
+        match zk_client.exists(znode_path, false) {
+            Ok(Some(_)) => existing_roots.push(*znode_path),
+            Ok(None) => {}
+            Err(err) => return record_failure(outcome, FailurePolicy::FailFast, "back up", znode_path, err),
+        }
+    }
+    let mut progress = Progress::new(quiet);
+    let options = DumpOptions { parallelism, ..Default::default() };
+    let backup = dump_znode_tree(zk_client, &existing_roots, backup_file, excluded_znodes, &options, &mut progress);
+    if backup.is_stopped() {
+        error!(event = "failed", file = backup_file; "Backup failed, nothing was changed");
+        for failure in backup.errors() {
+            outcome.stop(&failure.znode_path, format!("Backup to '{}' failed: {}", backup_file, failure.error));
+        }
+        return;
     }
+    progress.finish("Backed up", &backup.total());
 }
 
-fn delete_znode_tree(zk_client: &ZooKeeper, tree_root_znode_path: &str, excluded_znodes: &Vec<&str>) {
-    delete_znodes_recursively(zk_client, tree_root_znode_path, excluded_znodes);
+fn open_journal(journal_file: &Option<String>) -> Journal {
+    match *journal_file {
+        Some(ref journal_file) => Journal::create(journal_file)
+            .unwrap_or_else(|err| panic!("Can't create journal '{}'. Reason: '{}'", journal_file, err)),
+        None => Journal::disabled(),
+    }
 }
 
-fn delete_znodes_recursively(zk_client: &ZooKeeper, znode_path: &str, excluded_znodes: &Vec<&str>) {
-    if excluded_znodes.contains(&znode_path) {
+/// Records data and ACL the znode has right now, so that the upcoming change can be rolled back.
+fn journal_current_state(zk_client: &ZkClient, znode_path: &str, journal: &Journal, deleted: bool) -> ZkResult<()> {
+    if !journal.is_enabled() {
+        return Ok(());
+    }
+    let (data, stat) = zk_client.get_data(znode_path, false)?;
+    let (acl, _) = zk_client.get_acl(znode_path)?;
+    let path = String::from(znode_path);
+    let entry = if deleted {
+        let mode = ZnodeMeta::for_znode(znode_path, stat.ephemeral_owner).mode;
+        JournalEntry::Deleted { path, data, acl, mode }
+    } else {
+        JournalEntry::Updated { path, data, acl }
+    };
+    journal.record(&entry).expect("Can't write journal");
+    Ok(())
+}
+
+fn open_dump_file(dump_file: &str) -> Archive<GzDecoder<File>> {
+    let tar_gz = File::open(dump_file).expect("Can't read tar file");
+    Archive::new(GzDecoder::new(tar_gz))
+}
+
+fn is_selected_for_restore(znode_path: &str, znode_paths: &Vec<&str>, excluded_znodes: &Vec<&str>) -> bool {
+    let is_for_restoring = znode_paths.iter()
+        .any(|for_restoring| is_within(znode_path, for_restoring));
+    !is_excluded(znode_path, excluded_znodes) && is_for_restoring
+}
+
+fn is_excluded(znode_path: &str, excluded_znodes: &Vec<&str>) -> bool {
+    excluded_znodes.iter()
+        .any(|excluded| is_within(znode_path, excluded))
+}
+
+pub fn delete(connection: &ConnectionSettings, znode_paths: Vec<&str>, excluded_znodes: Vec<&str>, options: DeleteOptions) -> Outcome {
+    let mut parameters = report_parameters(connection, &znode_paths, &excluded_znodes);
+    parameters.extend(vec![
+        ("check_versions", Json::from(options.check_versions)),
+        ("require_existing", Json::from(options.require_existing)),
+        ("on_ephemeral", Json::from(match options.ephemeral_policy {
+            EphemeralPolicy::Abort => "abort",
+            EphemeralPolicy::SkipAncestors => "skip",
+            EphemeralPolicy::Wait(_) => "wait",
+        })),
+        ("ephemeral_wait_ms", Json::from(match options.ephemeral_policy {
+            EphemeralPolicy::Wait(timeout) => Some(timeout.as_millis() as usize),
+            _ => None,
+        })),
+        ("backup_before", Json::from(options.backup_file.clone())),
+        ("journal", Json::from(options.journal_file.clone())),
+        ("keep_going", Json::from(options.failure_policy == FailurePolicy::KeepGoing)),
+        ("protected", paths_parameter(&options.protected_znodes)),
+    ]);
+    let report = Report::start("delete", parameters);
+    let mut outcome = Outcome::new(&znode_paths);
+    if fail_on_protected(&znode_paths, &excluded_znodes, &options.protected_znodes, &mut outcome) {
+        write_report(report, &options.report_file, &outcome, None);
+        return outcome;
+    }
+    let zk_client = ZkClient::connect(connection);
+    let mut existing_znode_paths = Vec::new();
+    let mut missing_znode_paths = Vec::new();
+    for znode_path in &znode_paths {
+        match zk_client.exists(znode_path, false) {
+            Ok(Some(_)) => existing_znode_paths.push(*znode_path),
+            Ok(None) => missing_znode_paths.push(*znode_path),
+            Err(err) => {
+                record_failure(&mut outcome, FailurePolicy::FailFast, "read", znode_path, err);
+                write_report(report, &options.report_file, &outcome, None);
+                return outcome;
+            }
+        }
+    }
+    for znode_path in &missing_znode_paths {
+        warn!(event = "missing", znode = *znode_path; "Znode to delete doesn't exist");
+        if options.require_existing {
+            outcome.fail(znode_path, ZkError::NoNode);
+        } else {
+            outcome.of(znode_path).missing += 1;
+        }
+    }
+    if options.require_existing && !missing_znode_paths.is_empty() {
+        write_report(report, &options.report_file, &outcome, None);
+        return outcome;
+    }
+    let mut plan = DeletionPlan { versions: if options.check_versions { Some(HashMap::new()) } else { None }, ephemeral: BTreeMap::new(), znodes: 0 };
+    for znode_path in &existing_znode_paths {
+        plan_deletion_recursively(&zk_client, znode_path, &excluded_znodes, &mut plan, &mut outcome);
+    }
+    if let EphemeralPolicy::Wait(timeout) = options.ephemeral_policy {
+        plan.ephemeral = wait_for_ephemeral_znodes(&zk_client, mem::take(&mut plan.ephemeral), timeout, &mut outcome);
+    }
+    if outcome.is_stopped() {
+        write_report(report, &options.report_file, &outcome, None);
+        return outcome;
+    }
+    if !plan.ephemeral.is_empty() && options.ephemeral_policy != EphemeralPolicy::SkipAncestors {
+        for (znode_path, session) in &plan.ephemeral {
+            error!(event = "ephemeral", znode = znode_path.as_str(), session = format_session(*session).as_str(); "Can't delete ephemeral znode");
+            outcome.fail(znode_path, format!("Ephemeral znode of session {}", format_session(*session)));
+        }
+        write_report(report, &options.report_file, &outcome, None);
+        return outcome;
+    }
+    if options.confirm && plan.znodes > 0 && !confirm(&format!("Delete {} znodes under {}?", plan.znodes, quoted(&existing_znode_paths))) {
+        outcome.abort();
+        write_report(report, &options.report_file, &outcome, None);
+        return outcome;
+    }
+    if let Some(ref backup_file) = options.backup_file {
+        backup_before_mutation(&zk_client, &existing_znode_paths, backup_file, &excluded_znodes, 1, options.quiet, &mut outcome);
+        if outcome.is_stopped() {
+            write_report(report, &options.report_file, &outcome, None);
+            return outcome;
+        }
+    }
+    let journal = open_journal(&options.journal_file);
+    for tree_root_znode_path in existing_znode_paths {
+        delete_znodes_recursively(&zk_client, tree_root_znode_path, &excluded_znodes, &plan, &journal, &mut outcome, options.failure_policy);
+        if outcome.is_stopped() {
+            break;
+        }
+    }
+    report_conflicts(&outcome);
+    write_report(report, &options.report_file, &outcome, options.backup_file.as_deref());
+    outcome
+}
+
+/// Records the roots touching protected znodes as failures, returning whether there are any.
+fn fail_on_protected(znode_paths: &[&str], excluded_znodes: &[&str], protected_znodes: &[String], outcome: &mut Outcome) -> bool {
+    let violations = protected_violations(znode_paths, excluded_znodes, protected_znodes);
+    for (znode_path, protected) in &violations {
+        error!(event = "protected", znode = *znode_path, protected = *protected; "Znode is protected");
+        outcome.fail(znode_path, format!("Touches protected znode '{}', pass --allow-protected to change it", protected));
+    }
+    !violations.is_empty()
+}
+
+fn paths_parameter(znode_paths: &[String]) -> Json {
+    Json::Array(znode_paths.iter().map(|znode_path| Json::from(znode_path.as_str())).collect())
+}
+
+fn quoted(znode_paths: &[&str]) -> String {
+    znode_paths.iter()
+        .map(|znode_path| format!("'{}'", znode_path))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Parameters of the run common to all operations.
+fn report_parameters(connection: &ConnectionSettings, znode_paths: &[&str], excluded_znodes: &[&str]) -> Vec<(&'static str, Json)> {
+    vec![
+        ("servers", Json::from(connection.servers.as_str())),
+        ("chroot", Json::from(connection.chroot.as_deref())),
+        ("znodes", Json::from(znode_paths)),
+        ("excluded", Json::from(excluded_znodes)),
+    ]
+}
+
+fn write_report(report: Report, report_file: &Option<String>, outcome: &Outcome, archive_file: Option<&str>) {
+    if let Some(ref report_file) = *report_file {
+        report.write(report_file, outcome, archive_file)
+            .unwrap_or_else(|err| panic!("Can't write report '{}'. Reason: '{}'", report_file, err));
+    }
+}
+
+/// Reverts the changes recorded in a journal of `restore` or `delete`, newest first, going on past znodes
+/// which can't be reverted. Deleted ephemeral and sequential znodes are reported as failures, as they can't
+/// be created again the way they were.
+pub fn rollback(connection: &ConnectionSettings, journal_file: &str) -> Outcome {
+    let zk_client = ZkClient::connect(connection);
+    let entries = read_journal(journal_file)
+        .unwrap_or_else(|err| panic!("Can't read journal '{}'. Reason: '{}'", journal_file, err));
+    let mut outcome = Outcome::new(&["/"]);
+    let mut chroot_client = None;
+    for entry in entries.iter().rev() {
+        let path = entry.path();
+        let result = match *entry {
+            JournalEntry::ChrootCreated { ref path } => {
+                let chroot_client = chroot_client.get_or_insert_with(|| ZkClient::connect(&ConnectionSettings { chroot: None, ..connection.clone() }));
+                match chroot_client.delete(path, None) {
+                    Err(ZkError::NoNode) => Ok(()),
+                    result => result.map(|_| outcome.of(path).deleted += 1),
+                }
+            }
+            JournalEntry::Created { ref path } => match zk_client.delete(path, None) {
+                Err(ZkError::NoNode) => Ok(()),
+                result => result.map(|_| outcome.of(path).deleted += 1),
+            },
+            JournalEntry::Updated { ref path, ref data, ref acl } => set_content(&zk_client, path, data, acl)
+                .map(|_| outcome.of(path).updated += 1),
+            JournalEntry::Deleted { ref path, ref data, ref acl, mode } => {
+                let create_mode = match mode {
+                    ZnodeMode::Persistent => CreateMode::Persistent,
+                    ZnodeMode::Container => CreateMode::Container,
+                    _ => {
+                        error!(event = "failed", znode = path.as_str(), mode = mode.name(); "Can't roll back deletion of znode");
+                        outcome.fail(path, format!("Deleted {} znode can't be created again", mode.name()));
+                        continue;
+                    }
+                };
+                match zk_client.create(path, data.clone(), acl.clone(), create_mode) {
+                    Err(ZkError::NodeExists) => set_content(&zk_client, path, data, acl).map(|_| outcome.of(path).updated += 1),
+                    result => result.map(|_| outcome.of(path).created += 1),
+                }
+            }
+        };
+        if let Err(err) = result {
+            record_failure(&mut outcome, FailurePolicy::KeepGoing, "roll back", path, err);
+            if outcome.is_stopped() {
+                break;
+            }
+        }
+    }
+    outcome
+}
+
+fn set_content(zk_client: &ZkClient, znode_path: &str, data: &[u8], acl: &[Acl]) -> ZkResult<()> {
+    zk_client.set_data(znode_path, data.to_vec(), None)?;
+    zk_client.set_acl(znode_path, acl.to_vec(), None)?;
+    Ok(())
+}
+
+/// State of the trees to delete captured before any mutation.
+struct DeletionPlan {
+    /// Versions the znodes are only deleted in, if they are checked
+    versions: Option<HashMap<String, i32>>,
+    /// Ephemeral znodes with their owner sessions
+    ephemeral: BTreeMap<String, i64>,
+    /// How many of the other znodes there are
+    znodes: usize,
+}
+
+/// Captures the state of a tree to delete, stopping `outcome` if a znode can't be read.
+fn plan_deletion_recursively(zk_client: &ZkClient, znode_path: &str, excluded_znodes: &Vec<&str>, plan: &mut DeletionPlan, outcome: &mut Outcome) {
+    if excluded_znodes.contains(&znode_path) || outcome.is_stopped() {
         return;
     }
-    let (_, stat) = zk_client.get_data(znode_path, false).unwrap();
-    if stat.is_ephemeral() {
-        panic!(format!("'{}' is an ephemeral znode and can't be removed", znode_path));
+    let stat = match zk_client.exists(znode_path, false) {
+        Ok(Some(stat)) => stat,
+        Ok(None) => return,
+        Err(err) => return record_failure(outcome, FailurePolicy::FailFast, "read", znode_path, err),
+    };
+    if let Some(ref mut versions) = plan.versions {
+        versions.insert(String::from(znode_path), stat.version);
     }
-    let children = zk_client.get_children(znode_path, false).unwrap();
+    if let Owner::Session(session) = Owner::from_ephemeral_owner(stat.ephemeral_owner) {
+        // Ephemeral znodes can't have children
+        plan.ephemeral.insert(String::from(znode_path), session);
+        return;
+    }
+    plan.znodes += 1;
+    let children = match zk_client.get_children(znode_path, false) {
+        Ok(children) => children,
+        Err(ZkError::NoNode) => return,
+        Err(err) => return record_failure(outcome, FailurePolicy::FailFast, "list children of", znode_path, err),
+    };
     let current_path = ensure_ends_with_slash(znode_path);
     children.iter()
         .map(|child| current_path.clone() + child)
-        .for_each(|child| delete_znodes_recursively(zk_client, child.as_str(), excluded_znodes));
-    match znode_path {
-        "/" => { zk_client.set_data(znode_path, Vec::new(), Option::None).expect("Can't clear root znode data"); }
-        _ => match zk_client.delete(znode_path, Option::None) {
-            Ok(_) => {}
-            Err(err) => println!("Deleting znode '{}' failed. Reason: '{}'", znode_path, err.to_string()),
+        .for_each(|child| plan_deletion_recursively(zk_client, child.as_str(), excluded_znodes, plan, outcome));
+}
+
+/// Polls ephemeral znodes until they are gone, returning those still there after `timeout`
+/// or when one can't be read, which stops `outcome`.
+fn wait_for_ephemeral_znodes(zk_client: &ZkClient, ephemeral_znodes: BTreeMap<String, i64>, timeout: Duration,
+                             outcome: &mut Outcome) -> BTreeMap<String, i64> {
+    let started = Instant::now();
+    let mut remaining = ephemeral_znodes;
+    if !remaining.is_empty() {
+        info!(event = "waiting", znodes = remaining.len(), timeout_ms = timeout.as_millis() as u64; "Waiting for ephemeral znodes to go away");
+    }
+    loop {
+        remaining.retain(|znode_path, session| match zk_client.exists(znode_path, false) {
+            Ok(Some(stat)) => match Owner::from_ephemeral_owner(stat.ephemeral_owner) {
+                Owner::Session(owner) => {
+                    // Possibly created again by another session
+                    *session = owner;
+                    true
+                }
+                _ => false,
+            },
+            Ok(None) => false,
+            Err(err) => {
+                if !outcome.is_stopped() {
+                    record_failure(outcome, FailurePolicy::FailFast, "read", znode_path, err);
+                }
+                true
+            }
+        });
+        let elapsed = started.elapsed();
+        if remaining.is_empty() || elapsed >= timeout || outcome.is_stopped() {
+            return remaining;
         }
+        thread::sleep(EPHEMERAL_POLL_INTERVAL.min(timeout - elapsed));
+    }
+}
+
+/// Deletes a tree bottom-up, returning whether the znode itself is gone.
+fn delete_znodes_recursively(zk_client: &ZkClient, znode_path: &str, excluded_znodes: &Vec<&str>, plan: &DeletionPlan, journal: &Journal,
+                             outcome: &mut Outcome, failure_policy: FailurePolicy) -> bool {
+    let planned_versions = plan.versions.as_ref();
+    if excluded_znodes.contains(&znode_path) {
+        info!(event = "excluded", znode = znode_path; "Znode excluded");
+        outcome.of(znode_path).excluded += 1;
+        return false;
+    }
+    let stat = match zk_client.get_data(znode_path, false) {
+        Ok((_, stat)) => stat,
+        Err(ZkError::NoNode) if planned_versions.is_some() => {
+            outcome.conflict(znode_path, ZkError::NoNode);
+            return false;
+        }
+        Err(err) => {
+            record_failure(outcome, failure_policy, "read", znode_path, err);
+            return false;
+        }
+    };
+    debug!(event = "visited", znode = znode_path; "Znode visited");
+    if Owner::from_ephemeral_owner(stat.ephemeral_owner).is_ephemeral() {
+        if plan.ephemeral.contains_key(znode_path) {
+            info!(event = "skipped", znode = znode_path; "Ephemeral znode skipped along with its ancestors");
+            outcome.of(znode_path).skipped += 1;
+        } else {
+            // Created after planning, so it is someone else's znode
+            outcome.conflict(znode_path, "Ephemeral znode created after planning");
+        }
+        return false;
+    }
+    let children = match zk_client.get_children(znode_path, false) {
+        Ok(children) => children,
+        Err(err) => {
+            record_failure(outcome, failure_policy, "list children of", znode_path, err);
+            return false;
+        }
+    };
+    let current_path = ensure_ends_with_slash(znode_path);
+    let mut all_children_deleted = true;
+    for child in children {
+        let child_path = current_path.clone() + &child;
+        all_children_deleted &= delete_znodes_recursively(zk_client, child_path.as_str(), excluded_znodes, plan, journal, outcome, failure_policy);
+        if outcome.is_stopped() {
+            return false;
+        }
+    }
+    if !all_children_deleted && znode_path != "/" {
+        // Why the children were kept is recorded already
+        return false;
+    }
+    let version = match planned_versions.map(|versions| versions.get(znode_path)) {
+        Some(Some(version)) => Some(*version),
+        Some(None) => {
+            // Created after planning, so it is someone else's znode
+            outcome.conflict(znode_path, "Created after planning");
+            return false;
+        }
+        None => None,
     };
+    let result = journal_current_state(zk_client, znode_path, journal, znode_path != "/")
+        .and_then(|_| match znode_path {
+            "/" => zk_client.set_data(znode_path, Vec::new(), version).map(|_| ()),
+            _ => zk_client.delete(znode_path, version),
+        });
+    match result {
+        Ok(_) => {
+            info!(event = "deleted", znode = znode_path; "Znode deleted");
+            outcome.of(znode_path).deleted += 1;
+            true
+        }
+        Err(ref err) if version.is_some() && is_version_conflict(err) => {
+            outcome.conflict(znode_path, err);
+            false
+        }
+        Err(ZkError::NoNode) => {
+            // Deleted by someone else, or by an attempt whose reply was lost
+            info!(event = "vanished", znode = znode_path; "Znode deleted meanwhile");
+            outcome.of(znode_path).missing += 1;
+            true
+        }
+        Err(err) => {
+            record_failure(outcome, failure_policy, "delete", znode_path, err);
+            false
+        }
+    }
+}
+
+/// Errors meaning the znode was changed by someone else after its state had been planned.
+fn is_version_conflict(err: &ZkError) -> bool {
+    matches!(*err, ZkError::BadVersion | ZkError::NodeExists | ZkError::NoNode | ZkError::NotEmpty)
 }
 
-fn create_znodes_for_path(zk_client: &ZooKeeper, path: &str, data: Vec<u8>) {
-    let split: Vec<&str> = path.split('/').collect();
-    for i in 1..split.len() {
-        let new_znode = path_from_n_first_znodes(&split, i);
-        match zk_client.create(new_znode.as_str(), vec![], Acl::open_unsafe().clone(), CreateMode::Persistent) {
-            _ => {} //Probably fine if we can't create it
+fn report_conflicts(outcome: &Outcome) {
+    for conflict in outcome.conflicts() {
+        warn!(event = "conflict", znode = conflict.znode_path.as_str(), reason = conflict.error.as_str();
+              "Znode was modified by someone else since planning and was left untouched");
+    }
+}
+
+/// Records a znode the operation failed on, stopping the operation on it unless it keeps going.
+/// A lost session fails every following operation, so it always stops.
+fn record_failure(outcome: &mut Outcome, failure_policy: FailurePolicy, action: &str, znode_path: &str, err: ZkError) {
+    error!(event = "failed", znode = znode_path, reason:% = err; "Can't {} znode", action);
+    if failure_policy == FailurePolicy::FailFast || err == ZkError::SessionExpired {
+        outcome.stop(znode_path, err);
+    } else {
+        outcome.fail(znode_path, err);
+    }
+}
+
+fn create_znodes_for_path(zk_client: &ZkClient, path: &str, data: Vec<u8>, meta: &ZnodeMeta,
+                          planned_state: Option<PlannedState>, journal: &Journal) -> ZkResult<Restored> {
+    let new_znode = String::from(path);
+    match planned_state {
+        None => {
+            match create_znode(zk_client, new_znode.as_str(), data.clone(), CreateMode::Persistent, meta) {
+                Ok(_) => {
+                    journal.record(&JournalEntry::Created { path: new_znode }).expect("Can't write journal");
+                    Ok(Restored::Created)
+                }
+                Err(ZkError::NodeExists) => {
+                    journal_current_state(zk_client, new_znode.as_str(), journal, false)?;
+                    zk_client.set_data(new_znode.as_str(), data, Option::None).map(|_| Restored::Updated)
+                }
+                Err(err) => Err(err),
+            }
+        }
+        Some(PlannedState::Absent) => {
+            create_znode(zk_client, new_znode.as_str(), data, CreateMode::Persistent, meta)?;
+            journal.record(&JournalEntry::Created { path: new_znode }).expect("Can't write journal");
+            Ok(Restored::Created)
+        }
+        Some(PlannedState::Version(version)) => {
+            journal_current_state(zk_client, new_znode.as_str(), journal, false)?;
+            zk_client.set_data(new_znode.as_str(), data, Some(version)).map(|_| Restored::Updated)
+        }
+    }
+}
+
+/// Creates a sequential znode named like `znode_path` without its counter and returns the path it got.
+fn create_sequential_znode(zk_client: &ZkClient, znode_path: &str, data: Vec<u8>, meta: &ZnodeMeta, journal: &Journal) -> ZkResult<String> {
+    let name_prefix = &znode_path[..znode_path.len() - SEQUENCE_LENGTH];
+    let created_path = create_znode(zk_client, name_prefix, data, CreateMode::PersistentSequential, meta)?;
+    journal.record(&JournalEntry::Created { path: created_path.clone() }).expect("Can't write journal");
+    Ok(created_path)
+}
+
+/// Creates a container znode if it was one, otherwise or if the server doesn't support
+/// containers a znode of the given mode.
+fn create_znode(zk_client: &ZkClient, znode_path: &str, data: Vec<u8>, mode: CreateMode, meta: &ZnodeMeta) -> ZkResult<String> {
+    if meta.mode == ZnodeMode::Container {
+        match zk_client.create(znode_path, data.clone(), Acl::open_unsafe().clone(), CreateMode::Container) {
+            Err(ZkError::Unimplemented) => warn!(znode = znode_path; "Server doesn't support container znodes, the znode is created as a persistent one"),
+            result => return result,
+        }
+    }
+    zk_client.create(znode_path, data, Acl::open_unsafe().clone(), mode)
+}
+
+/// Returns how many znodes of every root were dumped, skipped and excluded. If the dump stopped on a failure,
+/// the outcome records it and no archive is written. How long the dump takes is told by the znode count of
+/// the base archive, or else of the archive it replaces.
+fn dump_znode_tree(zk_client: &ZkClient, tree_root_znode_paths: &Vec<&str>, dump_file: &str, excluded_znodes: &Vec<&str>, options: &DumpOptions,
+                   progress: &mut Progress) -> Outcome {
+    let keep_going = options.failure_policy == FailurePolicy::KeepGoing;
+    let (base, base_manifest) = match options.base_file {
+        Some(ref base_file) => {
+            let (base, base_manifest) = read_base(base_file);
+            (Some(base), Some(base_manifest))
+        }
+        None => (None, None),
+    };
+    let previous_znodes = match base_manifest {
+        Some(ref base_manifest) => base_manifest.znodes,
+        None if !progress.is_quiet() => read_manifest(dump_file).and_then(|manifest| manifest.znodes),
+        None => None,
+    };
+    if let Some(znodes) = previous_znodes {
+        progress.expect(znodes);
+    }
+    let base_zxid = base_manifest.and_then(|base_manifest| base_manifest.zxid);
+    let mut outcome = Outcome::default();
+    let result = write_archive(dump_file, |tar_archive| {
+        let mut dumped = BTreeMap::new();
+        let fetch = |znode_path: &str| match base {
+            Some(ref base) => fetch_changed_znode(zk_client, znode_path, excluded_znodes, options.include_ephemeral, base),
+            None => fetch_znode(zk_client, znode_path, excluded_znodes, options.include_ephemeral),
         };
+        outcome = traverse_with(tree_root_znode_paths, excluded_znodes, options.parallelism, keep_going, fetch, |znode_path, znode| {
+            progress.advance(1, znode.data.len());
+            if let Some(ref base) = base {
+                write_removed_children(znode_path, &znode, base, excluded_znodes, tar_archive)?;
+            }
+            write_znode(znode_path, znode, &mut dumped, tar_archive)
+        })?;
+        if outcome.is_stopped() {
+            return Err(Error::Stopped);
+        }
+        let zxid = fence(zk_client, &mut dumped, excluded_znodes, options, tar_archive, &mut outcome)?;
+        if outcome.is_stopped() {
+            return Err(Error::Stopped);
+        }
+        if let Some(zxid) = zxid {
+            info!(event = "fenced", zxid = format_zxid(zxid).as_str(); "Dump is consistent");
+        }
+        let manifest = Manifest { chroot: zk_client.chroot().map(String::from), zxid, base_zxid, znodes: Some(dumped.len()) };
+        write_tar_entry(MANIFEST_FILE_NAME, manifest.to_bytes(), tar_archive)
+    });
+    match result {
+        Ok(()) | Err(Error::Stopped) => {}
+        Err(err) => {
+            error!(event = "failed", file = dump_file, reason:% = err; "Dump failed");
+            outcome.stop(tree_root_znode_paths.first().cloned().unwrap_or("/"), err);
+        }
+    }
+    outcome
+}
+
+/// Writes the metadata of a znode along with its data, unless it is unchanged since the base archive.
+/// Restore ignores metadata without data, it only tells the next incremental dump what the znode was like.
+fn write_znode(znode_path: &str, znode: FetchedZnode, dumped: &mut BTreeMap<String, Zxids>, tar_archive: &mut Builder<GzEncoder<File>>) -> Result<()> {
+    let stat = znode.stat;
+    let meta = stat_meta(znode_path, &stat);
+    dumped.insert(String::from(znode_path), (stat.mzxid, stat.pzxid));
+    write_tar_entry(znode_path_to_meta_tar_path(znode_path).as_str(), meta.to_bytes(), tar_archive)?;
+    if znode.unchanged {
+        return Ok(());
+    }
+    info!(event = "written", znode = znode_path, bytes = znode.data.len(); "Znode written to archive");
+    write_tar_entry(znode_path_to_tar_path(znode_path).as_str(), znode.data, tar_archive)
+}
+
+fn stat_meta(znode_path: &str, stat: &Stat) -> ZnodeMeta {
+    ZnodeMeta { mzxid: Some(stat.mzxid), pzxid: Some(stat.pzxid), ..ZnodeMeta::for_znode(znode_path, stat.ephemeral_owner) }
+}
+
+fn write_tombstone(znode_path: &str, tar_archive: &mut Builder<GzEncoder<File>>) -> Result<()> {
+    write_tar_entry(znode_path_to_meta_tar_path(znode_path).as_str(), ZnodeMeta::tombstone().to_bytes(), tar_archive)?;
+    write_tar_entry(znode_path_to_tar_path(znode_path).as_str(), Vec::new(), tar_archive)
+}
+
+/// Writes tombstones of the children of a znode which are in the base archive, but not in the tree anymore.
+fn write_removed_children(znode_path: &str, znode: &FetchedZnode, base: &Base, excluded_znodes: &Vec<&str>,
+                          tar_archive: &mut Builder<GzEncoder<File>>) -> Result<()> {
+    let children: HashSet<&str> = znode.children.iter().map(String::as_str).collect();
+    for child in base.children.get(znode_path).into_iter().flatten() {
+        if !children.contains(child.as_str()) && !excluded_znodes.contains(&child.as_str()) {
+            info!(event = "removed", znode = child.as_str(); "Znode removed since the base archive");
+            write_tombstone(child, tar_archive)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads what an incremental dump needs to know about the znodes of its base archive, along with its manifest.
+/// Archives without a zxid in the manifest are taken to be consistent at the highest zxid of their znodes.
+fn read_base(base_file: &str) -> (Base, Manifest) {
+    let mut base = Base::default();
+    let mut manifest = Manifest::default();
+    let mut archive = open_dump_file(base_file);
+    for file in archive.entries().expect("Can't unpack tar file") {
+        let mut file = file.unwrap();
+        let tar_path = String::from(file.path().unwrap().to_str().unwrap());
+        if tar_path != MANIFEST_FILE_NAME && !tar_path.ends_with(META_FILE_NAME) {
+            continue;
+        }
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        if tar_path == MANIFEST_FILE_NAME {
+            manifest = Manifest::parse(&content).unwrap_or_else(|err| panic!("Can't read manifest of '{}'. Reason: '{}'", base_file, err));
+            continue;
+        }
+        let znode_path = tar_path_to_znode_path(&tar_path);
+        let meta = ZnodeMeta::parse(&content).unwrap_or_else(|err| panic!("Can't read metadata of znode '{}'. Reason: '{}'", znode_path, err));
+        let parent = parent_path(&znode_path).map(String::from);
+        if meta.mode == ZnodeMode::Deleted {
+            base.zxids.retain(|known_path, _| !is_within(known_path, &znode_path));
+            if let Some(siblings) = parent.and_then(|parent| base.children.get_mut(&parent)) {
+                siblings.remove(&znode_path);
+            }
+        } else if let (Some(mzxid), Some(pzxid)) = (meta.mzxid, meta.pzxid) {
+            base.zxids.insert(znode_path.clone(), (mzxid, pzxid));
+            if let Some(parent) = parent {
+                base.children.entry(parent).or_default().insert(znode_path);
+            }
+        }
+    }
+    if base.zxids.is_empty() {
+        warn!(file = base_file; "Base archive records no zxids, every znode is dumped");
+    }
+    let zxid = manifest.zxid.or_else(|| base.zxids.values().map(|(mzxid, pzxid)| *mzxid.max(pzxid)).max());
+    (base, Manifest { zxid, ..manifest })
+}
+
+/// Manifest of an existing archive, if there is one and it can be read.
+fn read_manifest(dump_file: &str) -> Option<Manifest> {
+    let mut archive = Archive::new(GzDecoder::new(File::open(dump_file).ok()?));
+    for file in archive.entries().ok()? {
+        let mut file = file.ok()?;
+        if file.path().ok()?.to_str() == Some(MANIFEST_FILE_NAME) {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).ok()?;
+            return Manifest::parse(&content).ok();
+        }
+    }
+    None
+}
+
+/// Re-reads the stats of the dumped znodes until none of them changed since it was written, so that the archive holds
+/// the trees as they were at one moment. Znodes changed meanwhile are written again along with their new subtrees and
+/// removed ones get tombstones, restore skips every entry of a znode but its last one wherever they are in the archive.
+/// Znodes which can't be read are recorded according to the failure policy, they are left out of further rounds if
+/// it keeps going. Returns the highest zxid of the dumped znodes, or nothing if they kept changing for `FENCE_ROUNDS`
+/// rounds or the outcome is stopped.
+fn fence(zk_client: &ZkClient, dumped: &mut BTreeMap<String, Zxids>, excluded_znodes: &Vec<&str>, options: &DumpOptions,
+         tar_archive: &mut Builder<GzEncoder<File>>, outcome: &mut Outcome) -> Result<Option<i64>> {
+    for _ in 0..FENCE_ROUNDS {
+        let znode_paths: Vec<String> = dumped.keys().cloned().collect();
+        let stats = run_concurrently(znode_paths.clone(), options.parallelism, |znode_path| zk_client.exists(&znode_path, false));
+        let mut changed = Vec::new();
+        for (znode_path, stat) in znode_paths.into_iter().zip(stats) {
+            match stat {
+                Ok(Some(stat)) if (stat.mzxid, stat.pzxid) == dumped[&znode_path] => {}
+                Ok(_) => changed.push(znode_path),
+                Err(err) => {
+                    record_failure(outcome, options.failure_policy, "read", &znode_path, err);
+                    if outcome.is_stopped() {
+                        return Ok(None);
+                    }
+                    dumped.remove(&znode_path);
+                }
+            }
+        }
+        if changed.is_empty() {
+            return Ok(dumped.values().map(|(mzxid, pzxid)| *mzxid.max(pzxid)).max());
+        }
+        let mut added = Vec::new();
+        for znode_path in changed {
+            match fetch_znode(zk_client, &znode_path, excluded_znodes, options.include_ephemeral) {
+                Ok(Some(znode)) => {
+                    debug!(event = "changed", znode = znode_path.as_str(); "Znode changed while dumping");
+                    added.extend(znode.children.iter().filter(|child| !dumped.contains_key(*child)).cloned());
+                    write_znode(&znode_path, znode, dumped, tar_archive)?;
+                }
+                Ok(None) | Err(ZkError::NoNode) => {
+                    debug!(event = "removed", znode = znode_path.as_str(); "Znode removed while dumping");
+                    dumped.remove(&znode_path);
+                    write_tombstone(&znode_path, tar_archive)?;
+                }
+                Err(err) => {
+                    record_failure(outcome, options.failure_policy, "read", &znode_path, err);
+                    if outcome.is_stopped() {
+                        return Ok(None);
+                    }
+                    dumped.remove(&znode_path);
+                }
+            }
+        }
+        let added: Vec<&str> = added.iter().map(String::as_str).collect();
+        let keep_going = options.failure_policy == FailurePolicy::KeepGoing;
+        let added_outcome = traverse(zk_client, &added, excluded_znodes, options.include_ephemeral, options.parallelism, keep_going, |znode_path, znode| {
+            write_znode(znode_path, znode, dumped, tar_archive)
+        })?;
+        outcome.merge(added_outcome);
+        if outcome.is_stopped() {
+            return Ok(None);
+        }
+    }
+    warn!(rounds = FENCE_ROUNDS; "Znodes kept changing while dumping, the archive may not hold them as they were at one moment");
+    Ok(None)
+}
+
+/// Writes a gzipped tar archive into a temporary file next to `dump_file` and renames it into place once
+/// it is complete and synced, so that a failed or interrupted dump never replaces a previous good one.
+fn write_archive<F>(dump_file: &str, write_entries: F) -> Result<()> where F: FnOnce(&mut Builder<GzEncoder<File>>) -> Result<()> {
+    let temporary_file = temporary_path(dump_file);
+    let result = write_archive_file(&temporary_file, write_entries)
+        .and_then(|_| Ok(fs::rename(&temporary_file, dump_file)?));
+    match result {
+        Ok(_) => sync_directory(dump_file),
+        Err(_) => {
+            let _ = fs::remove_file(&temporary_file);
+        }
     }
-    let new_znode = path_from_n_first_znodes(&split, split.len() - 1);
-    zk_client.set_data(new_znode.as_str(), data, Option::None).unwrap();
+    result
 }
 
-fn path_from_n_first_znodes(split_path: &Vec<&str>, n: usize) -> String {
-    split_path.iter()
-        .skip(1)
-        .take(n)
-        .fold(String::new(), |acc, node| acc + "/" + node)
+fn write_archive_file<F>(archive_file: &Path, write_entries: F) -> Result<()> where F: FnOnce(&mut Builder<GzEncoder<File>>) -> Result<()> {
+    let file = File::create(archive_file)?;
+    let mut tar_archive = Builder::new(GzEncoder::new(file, Compression::fast()));
+    write_entries(tar_archive.borrow_mut())?;
+    tar_archive.into_inner()?.finish()?.sync_all()?;
+    Ok(())
 }
 
-fn dump_znode_tree(zk_client: &ZooKeeper, tree_root_znode_path: &str, dump_file: &str, excluded_znodes: &Vec<&str>) {
-    let dump_file = File::create(dump_file).expect(format!("Can't create file '{}'", dump_file).as_str());
-    let enc = GzEncoder::new(dump_file, Compression::fast());
-    let mut tar_archive = tar::Builder::new(enc);
-    dump_znodes_recursively(zk_client, tree_root_znode_path, excluded_znodes, tar_archive.borrow_mut());
-    tar_archive.finish().unwrap();
+/// Hidden file in the directory of `file`, so that renaming it to `file` is atomic.
+fn temporary_path(file: &str) -> PathBuf {
+    let path = Path::new(file);
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or(file);
+    path.with_file_name(format!(".{}.{}.partial", file_name, process::id()))
 }
 
-fn dump_znodes_recursively(zk_client: &ZooKeeper, znode_path: &str, excluded_znodes: &Vec<&str>, tar_archive: &mut Builder<GzEncoder<File>>) {
-    if excluded_znodes.contains(&znode_path) {
-        return;
-    }
-    let (data, stat) = zk_client.get_data(znode_path, false).unwrap();
-    if stat.is_ephemeral() {
-        return;
+/// Makes the rename durable, where directories can't be opened this is skipped.
+fn sync_directory(file: &str) {
+    let directory = match Path::new(file).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(directory) = File::open(directory) {
+        let _ = directory.sync_all();
     }
-    write_znode_data_to_tar(znode_path, data, tar_archive);
-    let children = zk_client.get_children(znode_path, false).unwrap();
-    let current_path = ensure_ends_with_slash(znode_path);
-    children.iter()
-        .map(|child| current_path.clone() + child)
-        .for_each(|child| dump_znodes_recursively(zk_client, child.as_str(), excluded_znodes, tar_archive));
 }
 
 fn ensure_ends_with_slash(znode_path: &str) -> String {
@@ -138,17 +1731,25 @@ fn ensure_ends_with_slash(znode_path: &str) -> String {
     current_path
 }
 
-fn write_znode_data_to_tar(znode_path: &str, data: Vec<u8>, tar_archive: &mut Builder<GzEncoder<File>>) {
+fn write_tar_entry(tar_path: &str, data: Vec<u8>, tar_archive: &mut Builder<GzEncoder<File>>) -> Result<()> {
     let mut header = Header::new_gnu();
     header.set_size(data.len() as u64);
     header.set_cksum();
-    let tar_path = znode_path_to_tar_path(znode_path);
-    tar_archive.append_data(&mut header, tar_path, data.as_slice()).unwrap();
+    tar_archive.append_data(&mut header, tar_path, data.as_slice())?;
+    Ok(())
 }
 
 fn znode_path_to_tar_path(znode_path: &str) -> String {
+    znode_path_to_tar_entry_path(znode_path, DATA_FILE_NAME)
+}
+
+fn znode_path_to_meta_tar_path(znode_path: &str) -> String {
+    znode_path_to_tar_entry_path(znode_path, META_FILE_NAME)
+}
+
+fn znode_path_to_tar_entry_path(znode_path: &str, file_name: &str) -> String {
     Option::from(String::from(znode_path))
-        .map(|path| path + "/____data")
+        .map(|path| path + "/" + file_name)
         .map(|path| path.chars()
             .skip_while(|char| char == &'/')
             .collect::<String>())
@@ -157,7 +1758,7 @@ fn znode_path_to_tar_path(znode_path: &str) -> String {
 
 fn tar_path_to_znode_path(tar_path: &str) -> String {
     Option::from(String::from(tar_path))
-        .map(|path| path.replace("____data", ""))
+        .map(|path| path.replace(DATA_FILE_NAME, "").replace(META_FILE_NAME, ""))
         .map(|path| {
             if path.ends_with("/") {
                 path.chars()
@@ -175,14 +1776,39 @@ fn tar_path_to_znode_path(tar_path: &str) -> String {
 mod tests {
     use std::time::Duration;
 
-    use zk_interaction::{delete, dump, restore, tar_path_to_znode_path, znode_path_to_tar_path};
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    use error::Error;
+    use change_log::Change;
+    use zk_interaction::{apply_restore, compact, delete, dump, latest_entries, open_dump_file, parent_path, plan_restore, read_manifest, read_restore_entries, renamed_path, restore, restore_levels, rollback, run_concurrently, tar_path_to_znode_path, temporary_path, unensured_ancestors, write_archive, write_tar_entry, znode_path_to_meta_tar_path, znode_path_to_tar_path, DeleteOptions, DumpOptions, EphemeralPolicy, FailurePolicy, RestoreEntry, RestoreOptions, SequentialPolicy, RESTORE_BATCH_ENTRIES};
+
+    use zookeeper::{Acl, CreateMode, ZkError, ZooKeeper};
+
+    use archive::{Manifest, ZnodeMeta, ZnodeMode, MANIFEST_FILE_NAME};
 
-    use super::zookeeper::{Acl, CreateMode, ZooKeeper};
+    use connection::{ConnectionSettings, RetryPolicy, ZkClient};
+    use journal::{Journal, JournalEntry};
+    use report::{Outcome, Report};
+    use tls::TlsSettings;
 
     fn zk_client() -> ZooKeeper {
         ZooKeeper::connect("localhost:2181", Duration::from_secs(15), |_| {}).unwrap()
     }
 
+    fn connection() -> ConnectionSettings {
+        ConnectionSettings {
+            servers: String::from("localhost:2181"),
+            chroot: None,
+            session_timeout: Duration::from_secs(15),
+            connect_timeout: Duration::from_secs(15),
+            retry_policy: RetryPolicy::default(),
+            tls: None,
+        }
+    }
+
     #[allow(unused_must_use)]
     #[test]
     pub fn test_dump_restore() {
@@ -198,17 +1824,51 @@ mod tests {
         zk.create(excluded_znode.0, excluded_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
         zk.create(ephemeral_znode.0, ephemeral_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Ephemeral);
 
-        dump("localhost:2181", vec![root_znode.0], dump_file, vec![excluded_znode.0]);
+        dump(&connection(), vec![root_znode.0], dump_file, vec![excluded_znode.0], DumpOptions { parallelism: 4, ..Default::default() });
         zk.delete(child_znode.0, None);
         zk.delete(excluded_znode.0, None);
         zk.delete(root_znode.0, None);
-        restore("localhost:2181", dump_file, vec![root_znode.0], vec![excluded_znode.0]);
+        restore(&connection(), dump_file, vec![root_znode.0], vec![excluded_znode.0], RestoreOptions::default());
 
         assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, child_znode.1);
         assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, root_znode.1);
         assert!(zk.exists(excluded_znode.0, false).unwrap().is_none())
     }
 
+    #[test]
+    pub fn test_dump_absent_root() {
+        let dump_file = "test-dump-absent-root.tar.gz";
+        let outcome = dump(&connection(), vec!["/test_absent2134234"], dump_file, vec![], DumpOptions::default());
+
+        assert!(outcome.is_stopped());
+        assert_eq!(outcome.errors()[0].znode_path, "/test_absent2134234");
+        assert!(!Path::new(dump_file).exists());
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_dump_over_tls() {
+        let zk = zk_client();
+        let dump_file = "test-dump-tls.tar.gz";
+        let root_znode = ("/test_tls2134234", b"123data!".to_vec());
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        let tls_connection = ConnectionSettings {
+            servers: String::from("localhost:2281"),
+            tls: Some(TlsSettings {
+                ca_file: Some(String::from("test-tls/ca.pem")),
+                cert_file: Some(String::from("test-tls/client.pem")),
+                key_file: Some(String::from("test-tls/client.key")),
+            }),
+            ..connection()
+        };
+
+        let outcome = dump(&tls_connection, vec![root_znode.0], dump_file, vec![], DumpOptions::default());
+
+        assert!(outcome.errors().is_empty());
+        assert!(!outcome.is_stopped());
+        assert!(Path::new(dump_file).exists());
+    }
+
     #[allow(unused_must_use)]
     #[test]
     pub fn test_delete() {
@@ -221,13 +1881,659 @@ mod tests {
         zk.create(child_znode.0, child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
         zk.create(excluded_znode.0, excluded_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
 
-        delete("localhost:2181", vec![root_znode.0], vec![excluded_znode.0]);
+        delete(&connection(), vec![root_znode.0], vec![excluded_znode.0], DeleteOptions::default());
 
         assert!(zk.exists(child_znode.0, false).unwrap().is_none());
         assert!(zk.exists(root_znode.0, false).unwrap().is_some());
         assert!(zk.exists(excluded_znode.0, false).unwrap().is_some())
     }
 
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_delete_missing_root() {
+        let zk = zk_client();
+        let root_znode = ("/test_deletion_missing2134234", b"123data!".to_vec());
+        let missing_znode = "/test_deletion_missing2134234_absent";
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        let outcome = delete(&connection(), vec![root_znode.0, missing_znode], vec![], DeleteOptions { require_existing: true, ..Default::default() });
+        assert!(zk.exists(root_znode.0, false).unwrap().is_some());
+        assert_eq!(outcome.errors().len(), 1);
+
+        let outcome = delete(&connection(), vec![root_znode.0, missing_znode], vec![], DeleteOptions::default());
+        assert!(zk.exists(root_znode.0, false).unwrap().is_none());
+        assert!(outcome.errors().is_empty());
+        assert_eq!((outcome.total().deleted, outcome.total().missing), (1, 1));
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_delete_with_ephemeral() {
+        let zk = zk_client();
+        let root_znode = ("/test_deletion_ephemeral2134234", b"123data!".to_vec());
+        let parent_znode = ("/test_deletion_ephemeral2134234/1", b"123data!+1".to_vec());
+        let ephemeral_znode = ("/test_deletion_ephemeral2134234/1/1", b"123data!+1+1".to_vec());
+        let sibling_znode = ("/test_deletion_ephemeral2134234/2", b"123data!+2".to_vec());
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(parent_znode.0, parent_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(ephemeral_znode.0, ephemeral_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Ephemeral);
+        zk.create(sibling_znode.0, sibling_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        let outcome = delete(&connection(), vec![root_znode.0], vec![], DeleteOptions::default());
+        assert!(zk.exists(sibling_znode.0, false).unwrap().is_some());
+        assert_eq!(outcome.errors().iter().map(|failure| failure.znode_path.as_str()).collect::<Vec<_>>(), [ephemeral_znode.0]);
+
+        let options = DeleteOptions { ephemeral_policy: EphemeralPolicy::Wait(Duration::from_millis(100)), ..Default::default() };
+        let outcome = delete(&connection(), vec![root_znode.0], vec![], options);
+        assert!(zk.exists(sibling_znode.0, false).unwrap().is_some());
+        assert_eq!(outcome.errors().len(), 1);
+
+        let options = DeleteOptions { ephemeral_policy: EphemeralPolicy::SkipAncestors, ..Default::default() };
+        let outcome = delete(&connection(), vec![root_znode.0], vec![], options);
+        assert!(zk.exists(sibling_znode.0, false).unwrap().is_none());
+        assert!(zk.exists(ephemeral_znode.0, false).unwrap().is_some());
+        assert!(zk.exists(root_znode.0, false).unwrap().is_some());
+        assert_eq!((outcome.total().deleted, outcome.total().skipped), (1, 1));
+        assert!(outcome.errors().is_empty());
+
+        zk.delete(ephemeral_znode.0, None);
+        delete(&connection(), vec![root_znode.0], vec![], DeleteOptions::default());
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_delete_protected() {
+        let zk = zk_client();
+        let root_znode = ("/test_deletion_protected2134234", b"123data!".to_vec());
+        let protected_znode = ("/test_deletion_protected2134234/1", b"123data!+1".to_vec());
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(protected_znode.0, protected_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        let options = DeleteOptions { protected_znodes: vec![String::from(protected_znode.0)], ..Default::default() };
+        let outcome = delete(&connection(), vec![root_znode.0], vec![], options);
+        assert!(zk.exists(protected_znode.0, false).unwrap().is_some());
+        assert_eq!(outcome.errors().len(), 1);
+
+        let options = DeleteOptions { protected_znodes: vec![String::from(protected_znode.0)], ..Default::default() };
+        delete(&connection(), vec![root_znode.0], vec![protected_znode.0], options);
+        assert!(zk.exists(protected_znode.0, false).unwrap().is_some());
+
+        delete(&connection(), vec![root_znode.0], vec![], DeleteOptions::default());
+        assert!(zk.exists(root_znode.0, false).unwrap().is_none());
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_check_versions() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-versions.tar.gz";
+        let root_znode = ("/test_versions2134234", b"123data!".to_vec());
+        let child_znode = ("/test_versions2134234/1", b"123data!+1".to_vec());
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(child_znode.0, child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], DumpOptions::default());
+        zk.delete(child_znode.0, None);
+        zk.set_data(root_znode.0, b"changed".to_vec(), None);
+        restore(&connection(), dump_file, vec![root_znode.0], vec![], RestoreOptions { check_versions: true, ..Default::default() });
+
+        assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, child_znode.1);
+        assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, root_znode.1);
+
+        // Changed by someone else between planning and writing
+        let zk_client = ZkClient::connect(&connection());
+        let roots = vec![root_znode.0];
+        let mut outcome = Outcome::new(&roots);
+        let plan = plan_restore(&zk_client, dump_file, &roots, &vec![], 1, &mut outcome);
+        zk.set_data(root_znode.0, b"changed".to_vec(), None);
+        zk.delete(child_znode.0, None);
+        zk.create(child_znode.0, b"created".to_vec(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        let options = RestoreOptions { check_versions: true, ..Default::default() };
+        let outcome = apply_restore(&zk_client, dump_file, &roots, &vec![], &options, Some(&plan), &Journal::disabled(), outcome);
+
+        assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, b"changed".to_vec());
+        assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, b"created".to_vec());
+        let conflicts: Vec<&str> = outcome.conflicts().iter().map(|conflict| conflict.znode_path.as_str()).collect();
+        assert_eq!(conflicts, [root_znode.0, child_znode.0]);
+        assert!(outcome.errors().is_empty());
+        assert_eq!(outcome.exit_code(), 2);
+        let report_file = "test-report-versions.json";
+        Report::start("restore", vec![]).write(report_file, &outcome, Some(dump_file)).unwrap();
+        let report = fs::read_to_string(report_file).unwrap();
+        fs::remove_file(report_file);
+        assert!(report.contains(&format!("{{\"znode\":\"{}\",\"error\":\"BadVersion\",\"conflict\":true}}", root_znode.0)));
+
+        delete(&connection(), vec![root_znode.0], vec![], DeleteOptions { check_versions: true, ..Default::default() });
+        assert!(zk.exists(root_znode.0, false).unwrap().is_none())
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_backup_before() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-backup.tar.gz";
+        let backup_file = "test-backup-file.tar.gz";
+        let root_znode = ("/test_backup2134234", b"123data!".to_vec());
+        let child_znode = ("/test_backup2134234/1", b"123data!+1".to_vec());
+        let changed_data = b"changed".to_vec();
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(child_znode.0, child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], DumpOptions::default());
+        zk.set_data(child_znode.0, changed_data.clone(), None);
+        restore(&connection(), dump_file, vec![root_znode.0], vec![], RestoreOptions { backup_file: Some(String::from(backup_file)), ..Default::default() });
+        assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, child_znode.1);
+
+        restore(&connection(), backup_file, vec![root_znode.0], vec![], RestoreOptions::default());
+        assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, changed_data);
+
+        zk.delete(child_znode.0, None);
+        zk.delete(root_znode.0, None);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_rollback() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-rollback.tar.gz";
+        let restore_journal = "test-restore-journal.log";
+        let delete_journal = "test-delete-journal.log";
+        let root_znode = ("/test_rollback2134234", b"123data!".to_vec());
+        let child_znode = ("/test_rollback2134234/1", b"123data!+1".to_vec());
+        let changed_data = b"changed".to_vec();
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(child_znode.0, child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], DumpOptions::default());
+        zk.delete(child_znode.0, None);
+        zk.set_data(root_znode.0, changed_data.clone(), None);
+
+        restore(&connection(), dump_file, vec![root_znode.0], vec![], RestoreOptions { journal_file: Some(String::from(restore_journal)), ..Default::default() });
+        assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, root_znode.1);
+        assert!(rollback(&connection(), restore_journal).errors().is_empty());
+        assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, changed_data);
+        assert!(zk.exists(child_znode.0, false).unwrap().is_none());
+
+        zk.create(child_znode.0, child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        delete(&connection(), vec![root_znode.0], vec![], DeleteOptions { journal_file: Some(String::from(delete_journal)), ..Default::default() });
+        assert!(zk.exists(root_znode.0, false).unwrap().is_none());
+        let outcome = rollback(&connection(), delete_journal);
+        assert!(outcome.errors().is_empty());
+        assert_eq!(outcome.total().created, 2);
+        assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, changed_data);
+        assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, child_znode.1);
+
+        zk.delete(child_znode.0, None);
+        zk.delete(root_znode.0, None);
+    }
+
+    #[test]
+    pub fn test_rollback_ephemeral() {
+        let zk = zk_client();
+        let journal_file = "test-rollback-ephemeral-journal.log";
+        let ephemeral_znode = "/test_rollback_ephemeral2134234";
+        let journal = Journal::create(journal_file).unwrap();
+        journal.record(&JournalEntry::Deleted { path: String::from(ephemeral_znode), data: vec![], acl: Acl::open_unsafe().clone(), mode: ZnodeMode::Ephemeral })
+            .unwrap();
+
+        let outcome = rollback(&connection(), journal_file);
+        fs::remove_file(journal_file).unwrap();
+
+        assert_eq!(outcome.errors()[0].znode_path, ephemeral_znode);
+        assert!(zk.exists(ephemeral_znode, false).unwrap().is_none());
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_sequential() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-sequential.tar.gz";
+        let root_znode = ("/test_sequential2134234", b"123data!".to_vec());
+        let sequential_prefix = "/test_sequential2134234/lock-";
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        let first = zk.create(sequential_prefix, b"first".to_vec(), Acl::open_unsafe().clone(), CreateMode::PersistentSequential).unwrap();
+        let second = zk.create(sequential_prefix, b"second".to_vec(), Acl::open_unsafe().clone(), CreateMode::PersistentSequential).unwrap();
+
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], DumpOptions::default());
+        zk.delete(first.as_str(), None);
+        zk.delete(second.as_str(), None);
+        restore(&connection(), dump_file, vec![root_znode.0], vec![], RestoreOptions {
+            sequential_policy: SequentialPolicy::Recreate,
+            sequential_parents: vec![String::from(root_znode.0)],
+            parallelism: 4,
+            ..Default::default()
+        });
+
+        let mut children = zk.get_children(root_znode.0, false).unwrap();
+        children.sort();
+        assert_eq!(children.len(), 2);
+        assert!(children.iter().all(|child| child.starts_with("lock-")));
+        assert!(!children.iter().any(|child| root_znode.0.to_string() + "/" + child == first || root_znode.0.to_string() + "/" + child == second));
+        assert_eq!(zk.get_data((root_znode.0.to_string() + "/" + &children[0]).as_str(), false).unwrap().0, b"first".to_vec());
+        assert_eq!(zk.get_data((root_znode.0.to_string() + "/" + &children[1]).as_str(), false).unwrap().0, b"second".to_vec());
+
+        delete(&connection(), vec![root_znode.0], vec![], DeleteOptions::default());
+        zk.delete(root_znode.0, None);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_container() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-container.tar.gz";
+        let container_znode = ("/test_container2134234", b"123data!".to_vec());
+        let child_znode = ("/test_container2134234/1", b"123data!+1".to_vec());
+
+        zk.create(container_znode.0, container_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Container);
+        zk.create(child_znode.0, child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        dump(&connection(), vec![container_znode.0], dump_file, vec![], DumpOptions::default());
+        delete(&connection(), vec![container_znode.0], vec![], DeleteOptions::default());
+        assert!(zk.exists(container_znode.0, false).unwrap().is_none());
+        restore(&connection(), dump_file, vec![container_znode.0], vec![], RestoreOptions::default());
+
+        let (data, stat) = zk.get_data(container_znode.0, false).unwrap();
+        assert_eq!(data, container_znode.1);
+        assert_eq!(stat.ephemeral_owner, i64::MIN);
+        assert_eq!(zk.get_data(child_znode.0, false).unwrap().0, child_znode.1);
+
+        zk.delete(child_znode.0, None);
+        zk.delete(container_znode.0, None);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_dump_include_ephemeral() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-ephemeral.tar.gz";
+        let root_znode = ("/test_ephemeral2134234", b"123data!".to_vec());
+        let ephemeral_znode = ("/test_ephemeral2134234/ephemera-znode", b"data".to_vec());
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(ephemeral_znode.0, ephemeral_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Ephemeral);
+
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], DumpOptions { include_ephemeral: true, ..Default::default() });
+        let mut archive = open_dump_file(dump_file);
+        let ephemeral_meta = archive.entries().unwrap()
+            .map(|file| file.unwrap())
+            .find(|file| file.path().unwrap().to_str().unwrap() == "test_ephemeral2134234/ephemera-znode/____meta")
+            .map(|mut file| {
+                let mut meta = String::new();
+                file.read_to_string(&mut meta).unwrap();
+                meta
+            });
+        assert!(ephemeral_meta.unwrap().starts_with("mode=ephemeral\nsession=0x"));
+
+        zk.delete(ephemeral_znode.0, None);
+        restore(&connection(), dump_file, vec![root_znode.0], vec![], RestoreOptions { list_ephemeral: true, ..Default::default() });
+        assert!(zk.exists(ephemeral_znode.0, false).unwrap().is_none());
+
+        zk.delete(root_znode.0, None);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_dump_records_zxids() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-zxids.tar.gz";
+        let root_znode = ("/test_zxids2134234", b"123data!".to_vec());
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        let stat = zk.exists(root_znode.0, false).unwrap().unwrap();
+
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], DumpOptions::default());
+        let mut archive = open_dump_file(dump_file);
+        let files: Vec<(String, Vec<u8>)> = archive.entries().unwrap()
+            .map(|file| {
+                let mut file = file.unwrap();
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).unwrap();
+                (String::from(file.path().unwrap().to_str().unwrap()), content)
+            })
+            .collect();
+        let (last_path, manifest) = files.last().unwrap();
+        assert_eq!(last_path, MANIFEST_FILE_NAME);
+        assert_eq!(Manifest::parse(manifest).unwrap().zxid, Some(stat.mzxid.max(stat.pzxid)));
+        assert_eq!(Manifest::parse(manifest).unwrap().znodes, Some(1));
+        let meta = files.iter().find(|(path, _)| path == "test_zxids2134234/____meta").unwrap();
+        assert_eq!(ZnodeMeta::parse(&meta.1).unwrap().mzxid, Some(stat.mzxid));
+
+        zk.delete(root_znode.0, None);
+        fs::remove_file(dump_file);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_dump_incremental() {
+        let zk = zk_client();
+        let base_file = "test-dump-file-incremental-base.tar.gz";
+        let dump_file = "test-dump-file-incremental.tar.gz";
+        let root_znode = ("/test_incremental2134234", b"123data!".to_vec());
+        let changed_znode = ("/test_incremental2134234/changed", b"123data!+1".to_vec());
+        let removed_znode = ("/test_incremental2134234/removed", b"123data!+2".to_vec());
+
+        zk.create(root_znode.0, root_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(changed_znode.0, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(removed_znode.0, removed_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        dump(&connection(), vec![root_znode.0], base_file, vec![], DumpOptions::default());
+        zk.set_data(changed_znode.0, changed_znode.1.clone(), None);
+        zk.delete(removed_znode.0, None);
+        let options = DumpOptions { base_file: Some(String::from(base_file)), ..Default::default() };
+        dump(&connection(), vec![root_znode.0], dump_file, vec![], options);
+        zk.delete(changed_znode.0, None);
+        zk.delete(root_znode.0, None);
+
+        restore(&connection(), base_file, vec![root_znode.0], vec![], RestoreOptions::default());
+        restore(&connection(), dump_file, vec![root_znode.0], vec![], RestoreOptions::default());
+
+        assert_eq!(zk.get_data(root_znode.0, false).unwrap().0, root_znode.1);
+        assert_eq!(zk.get_data(changed_znode.0, false).unwrap().0, changed_znode.1);
+        assert!(zk.exists(removed_znode.0, false).unwrap().is_none());
+
+        zk.delete(changed_znode.0, None);
+        zk.delete(root_znode.0, None);
+        fs::remove_file(base_file);
+        fs::remove_file(dump_file);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_chroot() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-chroot.tar.gz";
+        let source_chroot = "/test_chroot_a2134234";
+        let target_chroot = "/test_chroot_b2134234/nested";
+        let child_znode = ("/1", b"123data!+1".to_vec());
+
+        zk.create(source_chroot, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent);
+        zk.create(&(String::from(source_chroot) + child_znode.0), child_znode.1.clone(), Acl::open_unsafe().clone(), CreateMode::Persistent);
+
+        let source = ConnectionSettings { chroot: Some(String::from(source_chroot)), ..connection() };
+        let target = ConnectionSettings { chroot: Some(String::from(target_chroot)), ..connection() };
+        dump(&source, vec!["/"], dump_file, vec![], DumpOptions::default());
+        let journal_file = "test-restore-chroot-journal.log";
+        restore(&target, dump_file, vec!["/"], vec![], RestoreOptions { journal_file: Some(String::from(journal_file)), ..Default::default() });
+
+        assert_eq!(zk.get_data(&(String::from(target_chroot) + child_znode.0), false).unwrap().0, child_znode.1);
+
+        let outcome = rollback(&target, journal_file);
+        fs::remove_file(journal_file);
+        fs::remove_file(dump_file);
+        assert!(outcome.errors().is_empty());
+        assert!(zk.exists("/test_chroot_b2134234", false).unwrap().is_none());
+
+        delete(&connection(), vec![source_chroot], vec![], DeleteOptions::default());
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_under_ephemeral() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-under-ephemeral.tar.gz";
+        let ephemeral_znode = "/test_under_ephemeral2134234";
+        let child_znode = "/test_under_ephemeral2134234/a/b";
+        write_archive(dump_file, |tar_archive| {
+            write_tar_entry(&znode_path_to_meta_tar_path(child_znode), ZnodeMeta::default().to_bytes(), tar_archive)?;
+            write_tar_entry(&znode_path_to_tar_path(child_znode), b"123data!".to_vec(), tar_archive)
+        }).unwrap();
+        zk.create(ephemeral_znode, vec![], Acl::open_unsafe().clone(), CreateMode::Ephemeral);
+
+        let outcome = restore(&connection(), dump_file, vec![child_znode], vec![], RestoreOptions::default());
+        fs::remove_file(dump_file);
+
+        let errors = outcome.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].znode_path, child_znode);
+        assert!(zk.exists(child_znode, false).unwrap().is_none());
+        zk.delete(ephemeral_znode, None);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn test_restore_rejects_ttl() {
+        let zk = zk_client();
+        let dump_file = "test-dump-file-ttl.tar.gz";
+        let root_znode = "/test_ttl2134234";
+        let ttl_znode = "/test_ttl2134234/1";
+        let child_znode = "/test_ttl2134234/1/a";
+        let other_znode = "/test_ttl2134234/2";
+        write_archive(dump_file, |tar_archive| {
+            for (znode_path, meta) in [
+                (root_znode, ZnodeMeta::default()),
+                (ttl_znode, ZnodeMeta { ttl: Some(60000), ..Default::default() }),
+                (child_znode, ZnodeMeta::default()),
+                (other_znode, ZnodeMeta::default()),
+            ] {
+                write_tar_entry(&znode_path_to_meta_tar_path(znode_path), meta.to_bytes(), tar_archive)?;
+                write_tar_entry(&znode_path_to_tar_path(znode_path), b"123data!".to_vec(), tar_archive)?;
+            }
+            Ok(())
+        }).unwrap();
+
+        let options = RestoreOptions { failure_policy: FailurePolicy::KeepGoing, ..Default::default() };
+        let mut outcome = restore(&connection(), dump_file, vec![root_znode], vec![], options);
+        fs::remove_file(dump_file);
+
+        let errors: Vec<&str> = outcome.errors().iter().map(|failure| failure.znode_path.as_str()).collect();
+        assert_eq!(errors, [ttl_znode]);
+        assert_eq!(outcome.of(root_znode).skipped, 1);
+        assert!(zk.exists(ttl_znode, false).unwrap().is_none());
+        assert!(zk.exists(other_znode, false).unwrap().is_some());
+        delete(&connection(), vec![root_znode], vec![], DeleteOptions::default());
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn read_restore_entries_supersedes() {
+        let dump_file = "test-dump-file-supersedes.tar.gz";
+        write_archive(dump_file, |tar_archive| {
+            for (znode_path, data, meta) in [
+                ("/a", "1", ZnodeMeta::default()),
+                ("/a/b", "2", ZnodeMeta::default()),
+                ("/a/c", "3", ZnodeMeta::default()),
+                ("/a", "4", ZnodeMeta::default()),
+                ("/a/b", "", ZnodeMeta::tombstone()),
+                ("/d", "", ZnodeMeta::tombstone()),
+            ] {
+                write_tar_entry(&znode_path_to_meta_tar_path(znode_path), meta.to_bytes(), tar_archive)?;
+                write_tar_entry(&znode_path_to_tar_path(znode_path), data.as_bytes().to_vec(), tar_archive)?;
+            }
+            Ok(())
+        }).unwrap();
+
+        let mut entries = Vec::new();
+        let latest = latest_entries(dump_file, &vec!["/"], &vec![]);
+        read_restore_entries(dump_file, &vec!["/"], &vec![], None, false, &latest, |batch| {
+            entries.extend(batch);
+            true
+        });
+        fs::remove_file(dump_file);
+        assert_eq!(latest.count(), 4);
+        let entries: Vec<(String, Vec<u8>, ZnodeMode)> = entries.into_iter().map(|entry| (entry.znode_path, entry.data, entry.meta.mode)).collect();
+        assert_eq!(entries, [
+            (String::from("/a/c"), b"3".to_vec(), ZnodeMode::Persistent),
+            (String::from("/a"), b"4".to_vec(), ZnodeMode::Persistent),
+            (String::from("/a/b"), b"".to_vec(), ZnodeMode::Deleted),
+            (String::from("/d"), b"".to_vec(), ZnodeMode::Deleted),
+        ]);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn read_restore_entries_supersedes_across_batches() {
+        let dump_file = "test-dump-file-supersedes-batches.tar.gz";
+        write_archive(dump_file, |tar_archive| {
+            write_tar_entry(&znode_path_to_tar_path("/a"), b"1".to_vec(), tar_archive)?;
+            for child in 0..RESTORE_BATCH_ENTRIES {
+                write_tar_entry(&znode_path_to_tar_path(&format!("/a/{}", child)), Vec::new(), tar_archive)?;
+            }
+            write_tar_entry(&znode_path_to_tar_path("/a"), b"2".to_vec(), tar_archive)?;
+            write_tar_entry(&znode_path_to_meta_tar_path("/a/0"), ZnodeMeta::tombstone().to_bytes(), tar_archive)?;
+            write_tar_entry(&znode_path_to_tar_path("/a/0"), Vec::new(), tar_archive)
+        }).unwrap();
+
+        let mut batches = 0;
+        let mut entries = Vec::new();
+        let latest = latest_entries(dump_file, &vec!["/a"], &vec![]);
+        read_restore_entries(dump_file, &vec!["/a"], &vec![], None, false, &latest, |batch| {
+            batches += 1;
+            entries.extend(batch);
+            true
+        });
+        fs::remove_file(dump_file);
+        assert_eq!(batches, 2);
+        assert_eq!(entries.len(), RESTORE_BATCH_ENTRIES + 1);
+        assert_eq!(latest.count(), RESTORE_BATCH_ENTRIES + 1);
+        let archived_a: Vec<&[u8]> = entries.iter().filter(|entry| entry.znode_path == "/a").map(|entry| entry.data.as_slice()).collect();
+        assert_eq!(archived_a, [b"2"]);
+        let archived_a0: Vec<ZnodeMode> = entries.iter().filter(|entry| entry.znode_path == "/a/0").map(|entry| entry.meta.mode).collect();
+        assert_eq!(archived_a0, [ZnodeMode::Deleted]);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn compact_applies_changes() {
+        let dump_file = "test-dump-file-compact.tar.gz";
+        write_archive(dump_file, |tar_archive| {
+            for (znode_path, data) in [("/a", "1"), ("/a/b", "2"), ("/a/c", "3")] {
+                write_tar_entry(&znode_path_to_meta_tar_path(znode_path), ZnodeMeta::default().to_bytes(), tar_archive)?;
+                write_tar_entry(&znode_path_to_tar_path(znode_path), data.as_bytes().to_vec(), tar_archive)?;
+            }
+            let manifest = Manifest { chroot: Some(String::from("/kafka")), zxid: Some(0x2a), base_zxid: Some(0x10), znodes: Some(3) };
+            write_tar_entry(MANIFEST_FILE_NAME, manifest.to_bytes(), tar_archive)
+        }).unwrap();
+
+        compact(dump_file, vec![
+            Change::Changed { path: String::from("/a/d"), meta: ZnodeMeta { mzxid: Some(0x2b), pzxid: Some(0x2b), ..Default::default() }, data: b"4".to_vec() },
+            Change::Changed { path: String::from("/a/b"), meta: ZnodeMeta::default(), data: b"5".to_vec() },
+            Change::Deleted { path: String::from("/a/c"), zxid: Some(0x2d) },
+            Change::Changed { path: String::from("/a/b"), meta: ZnodeMeta { mzxid: Some(0x2c), ..Default::default() }, data: b"6".to_vec() },
+        ]).unwrap();
+        let mut archive = open_dump_file(dump_file);
+        let files: Vec<(String, Vec<u8>)> = archive.entries().unwrap()
+            .map(|file| {
+                let mut file = file.unwrap();
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).unwrap();
+                (String::from(file.path().unwrap().to_str().unwrap()), content)
+            })
+            .filter(|(path, _)| !path.ends_with("____meta"))
+            .collect();
+        fs::remove_file(dump_file);
+        assert_eq!(files, [
+            (String::from("a/____data"), b"1".to_vec()),
+            (String::from("a/b/____data"), b"6".to_vec()),
+            (String::from("a/d/____data"), b"4".to_vec()),
+            (String::from(MANIFEST_FILE_NAME), b"chroot=/kafka\nzxid=0x2d\nbase_zxid=0x10\nznodes=3\n".to_vec()),
+        ]);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn latest_entries_count_and_manifest() {
+        let dump_file = "test-dump-file-count.tar.gz";
+        write_archive(dump_file, |tar_archive| {
+            let metas = [
+                ("/a", ZnodeMeta::default()),
+                ("/a/b", ZnodeMeta::tombstone()),
+                ("/a/c", ZnodeMeta::for_znode("/a/c", 0x2a)),
+                ("/a/excluded", ZnodeMeta::default()),
+                ("/d", ZnodeMeta::default()),
+            ];
+            for (znode_path, meta) in metas.iter() {
+                write_tar_entry(&znode_path_to_meta_tar_path(znode_path), meta.to_bytes(), tar_archive)?;
+                write_tar_entry(&znode_path_to_tar_path(znode_path), Vec::new(), tar_archive)?;
+            }
+            write_tar_entry(MANIFEST_FILE_NAME, Manifest { znodes: Some(4), ..Default::default() }.to_bytes(), tar_archive)
+        }).unwrap();
+
+        let count = latest_entries(dump_file, &vec!["/a"], &vec!["/a/excluded"]).count();
+        let manifest = read_manifest(dump_file);
+        fs::remove_file(dump_file);
+        assert_eq!(count, 2);
+        assert_eq!(manifest.unwrap().znodes, Some(4));
+        assert_eq!(read_manifest(dump_file), None);
+    }
+
+    #[allow(unused_must_use)]
+    #[test]
+    pub fn write_archive_keeps_previous_file() {
+        let dump_file = "test-dump-file-atomic.tar.gz";
+        write_archive(dump_file, |tar_archive| write_tar_entry("____data", b"123data!".to_vec(), tar_archive)).unwrap();
+        let previous = fs::read(dump_file).unwrap();
+
+        let result = write_archive(dump_file, |tar_archive| {
+            write_tar_entry("____data", b"123data!+1".to_vec(), tar_archive)?;
+            Err(Error::Zk(ZkError::SessionExpired))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(dump_file).unwrap(), previous);
+        assert!(!temporary_path(dump_file).exists());
+        fs::remove_file(dump_file);
+    }
+
+    #[test]
+    pub fn temporary_path_test() {
+        let pid = std::process::id();
+        assert_eq!(temporary_path("dump.tar.gz"), Path::new(&format!(".dump.tar.gz.{}.partial", pid)));
+        assert_eq!(temporary_path("/backups/dump.tar.gz"), Path::new(&format!("/backups/.dump.tar.gz.{}.partial", pid)));
+    }
+
+    #[test]
+    pub fn parent_path_test() {
+        assert_eq!(parent_path("/"), None);
+        assert_eq!(parent_path("/banana"), Some("/"));
+        assert_eq!(parent_path("/banana/split"), Some("/banana"));
+    }
+
+    #[test]
+    pub fn restore_levels_test() {
+        let entry = |znode_path: &str, mode: ZnodeMode| RestoreEntry {
+            znode_path: String::from(znode_path),
+            data: vec![],
+            meta: ZnodeMeta { mode, ..Default::default() },
+        };
+        let entries = || vec![
+            entry("/", ZnodeMode::Persistent),
+            entry("/locks", ZnodeMode::Persistent),
+            entry("/locks/a", ZnodeMode::Persistent),
+            entry("/locks/lock-0000000001", ZnodeMode::PersistentSequential),
+            entry("/locks/lock-0000000001/b", ZnodeMode::Persistent),
+            entry("/locks/lock-0000000002", ZnodeMode::PersistentSequential),
+            entry("/queue", ZnodeMode::Persistent),
+        ];
+        let paths = |levels: Vec<Vec<Vec<RestoreEntry>>>| levels.into_iter()
+            .map(|level| level.into_iter()
+                .map(|group| group.into_iter().map(|entry| entry.znode_path).collect::<Vec<String>>())
+                .collect::<Vec<Vec<String>>>())
+            .collect::<Vec<Vec<Vec<String>>>>();
+
+        let recreate = RestoreOptions { sequential_policy: SequentialPolicy::Recreate, sequential_parents: vec![String::from("/locks")], ..Default::default() };
+        assert_eq!(paths(restore_levels(entries(), &recreate)), vec![
+            vec![vec!["/"]],
+            vec![vec!["/locks"], vec!["/queue"]],
+            vec![vec!["/locks/a"], vec!["/locks/lock-0000000001", "/locks/lock-0000000002"]],
+            vec![vec!["/locks/lock-0000000001/b"]],
+        ]);
+        let separate = vec![vec!["/locks/a"], vec!["/locks/lock-0000000001"], vec!["/locks/lock-0000000002"]];
+        assert_eq!(paths(restore_levels(entries(), &RestoreOptions::default()))[2], separate);
+        let elsewhere = RestoreOptions { sequential_parents: vec![String::from("/queue")], ..recreate };
+        assert_eq!(paths(restore_levels(entries(), &elsewhere))[2], separate);
+    }
+
+    #[test]
+    pub fn run_concurrently_keeps_order() {
+        let results = run_concurrently((0..100).collect(), 8, |number| number * 2);
+        assert_eq!(results, (0..100).map(|number| number * 2).collect::<Vec<i32>>());
+    }
+
     #[allow(unused_must_use)]
     #[test]
     pub fn tar_path_to_znode_path_test() {
@@ -236,6 +2542,9 @@ mod tests {
 
         let zk_path = tar_path_to_znode_path("banana/____data");
         assert_eq!(zk_path, "/banana");
+
+        let zk_path = tar_path_to_znode_path("banana/____meta");
+        assert_eq!(zk_path, "/banana");
     }
 
     #[test]
@@ -245,6 +2554,32 @@ mod tests {
 
         let tar_path = znode_path_to_tar_path("/banana");
         assert_eq!(tar_path, "banana/____data");
+
+        let tar_path = znode_path_to_meta_tar_path("/banana");
+        assert_eq!(tar_path, "banana/____meta");
+    }
+
+    #[test]
+    pub fn renamed_path_test() {
+        let recreated: HashMap<String, String> = vec![
+            (String::from("/locks/lock-0000000001"), String::from("/locks/lock-0000000007")),
+            (String::from("/locks/lock-0000000001/sub-0000000003"), String::from("/locks/lock-0000000007/sub-0000000000")),
+        ].into_iter().collect();
+        assert_eq!(renamed_path("/locks/lock-0000000001/sub-0000000003/data", &recreated), "/locks/lock-0000000007/sub-0000000000/data");
+        assert_eq!(renamed_path("/locks/lock-0000000001/other", &recreated), "/locks/lock-0000000007/other");
+        assert_eq!(renamed_path("/locks/lock-00000000012", &recreated), "/locks/lock-00000000012");
+        assert_eq!(renamed_path("/banana", &recreated), "/banana");
+    }
+
+    #[test]
+    pub fn unensured_ancestors_test() {
+        let mut ensured_paths = HashSet::new();
+        assert_eq!(unensured_ancestors("/a", &ensured_paths), Vec::<String>::new());
+        assert_eq!(unensured_ancestors("/a/b/c", &ensured_paths), ["/a", "/a/b"]);
+        ensured_paths.insert(String::from("/a"));
+        assert_eq!(unensured_ancestors("/a/b/c", &ensured_paths), ["/a/b"]);
+        ensured_paths.insert(String::from("/a/b"));
+        assert!(unensured_ancestors("/a/b/c", &ensured_paths).is_empty());
     }
 }
 